@@ -0,0 +1,473 @@
+use crate::{
+    averages, duration,
+    metric::{Metric, Values},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::{Duration, Instant, SystemTime},
+};
+
+/// A threshold on one metric, from the config's `[alerts]` table
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    pub metric: Metric,
+    /// Fires when the value is above this
+    pub above: Option<f64>,
+    /// Fires when the value is below this
+    pub below: Option<f64>,
+    /// How long the threshold must be crossed before firing
+    #[serde(
+        default,
+        rename = "for",
+        deserialize_with = "duration::deserialize_option"
+    )]
+    pub duration: Option<Duration>,
+    /// Compare the mean over this window rather than each value
+    #[serde(default, deserialize_with = "duration::deserialize_option")]
+    pub mean: Option<Duration>,
+    /// How far back past the threshold the value must go to resolve
+    #[serde(default)]
+    pub hysteresis: f64,
+    /// The minimum time between firings for a device
+    #[serde(default, deserialize_with = "duration::deserialize_option")]
+    pub cooldown: Option<Duration>,
+    /// IDs of the devices the rule applies to, or every device if empty
+    #[serde(default)]
+    pub devices: Vec<String>,
+}
+
+impl Rule {
+    /// Checks everything about a loaded rule that deserializing doesn't
+    pub fn validate(&self) -> Result<(), String> {
+        match (self.above, self.below) {
+            (Some(_), None) | (None, Some(_)) => {}
+            _ => return Err(String::from("Exactly one of above and below must be set")),
+        }
+        if !(self.hysteresis >= 0.0 && self.hysteresis.is_finite()) {
+            return Err(format!(
+                "hysteresis must not be negative: {}",
+                self.hysteresis
+            ));
+        }
+        if self.mean == Some(Duration::ZERO) {
+            return Err(String::from("mean must be longer than 0s"));
+        }
+        Ok(())
+    }
+
+    pub fn applies_to(&self, device_id: &str) -> bool {
+        self.devices.is_empty() || self.devices.iter().any(|id| id == device_id)
+    }
+
+    pub fn threshold(&self) -> f64 {
+        self.above.or(self.below).unwrap_or_default()
+    }
+
+    fn is_crossed(&self, value: f64) -> bool {
+        match (self.above, self.below) {
+            (Some(above), _) => value > above,
+            (_, Some(below)) => value < below,
+            _ => false,
+        }
+    }
+
+    fn is_cleared(&self, value: f64) -> bool {
+        match (self.above, self.below) {
+            (Some(above), _) => value <= above - self.hysteresis,
+            (_, Some(below)) => value >= below + self.hysteresis,
+            _ => true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Firing,
+    Resolved,
+}
+
+impl State {
+    pub fn name(&self) -> &'static str {
+        match self {
+            State::Firing => "firing",
+            State::Resolved => "resolved",
+        }
+    }
+}
+
+/// A rule starting or stopping firing for a device
+#[derive(Debug, Clone, PartialEq)]
+pub struct Alert {
+    pub rule: String,
+    pub metric: Metric,
+    pub state: State,
+    /// The value, or mean, that caused the change
+    pub value: f64,
+    pub threshold: f64,
+}
+
+impl std::fmt::Display for Alert {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Alert {} {}: {} is {} (threshold {})",
+            self.rule,
+            self.state.name(),
+            self.metric,
+            self.value,
+            self.threshold
+        )
+    }
+}
+
+/// The windows of one rule with a `mean`, by device ID
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RuleMeans {
+    /// The rule's metric, since the windows are no use if it changes
+    metric: Option<Metric>,
+    devices: BTreeMap<String, averages::Window>,
+}
+
+/// The windows of rules with a `mean`, by rule name. This is kept in the
+/// state file so that long windows survive restarts and reloads.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Means(pub BTreeMap<String, RuleMeans>);
+
+impl Means {
+    /// Forgets rules that no longer have a mean and devices that are no
+    /// longer configured
+    pub fn retain<'a>(
+        &mut self,
+        rules: &BTreeMap<String, Rule>,
+        device_ids: impl IntoIterator<Item = &'a str>,
+    ) {
+        let ids = device_ids.into_iter().collect::<Vec<_>>();
+        self.0.retain(|name, means| {
+            means.devices.retain(|id, _| ids.contains(&id.as_str()));
+            rules
+                .get(name)
+                .is_some_and(|rule| rule.mean.is_some() && Some(rule.metric) == means.metric)
+        });
+    }
+}
+
+#[derive(Debug)]
+struct RuleState {
+    crossed_since: Option<Instant>,
+    firing: bool,
+    last_fired: Option<Instant>,
+}
+
+/// Evaluates alert rules against each device's values as they arrive
+#[derive(Debug, Default)]
+pub struct Engine {
+    rules: BTreeMap<String, Rule>,
+    /// By rule name then device ID
+    states: HashMap<(String, String), RuleState>,
+}
+
+impl Engine {
+    pub fn new(rules: BTreeMap<String, Rule>) -> Self {
+        Engine {
+            rules,
+            states: HashMap::new(),
+        }
+    }
+
+    pub fn rules(&self) -> &BTreeMap<String, Rule> {
+        &self.rules
+    }
+
+    /// Replaces the rules, keeping the state of any that are unchanged
+    pub fn set_rules(&mut self, rules: BTreeMap<String, Rule>) {
+        self.states
+            .retain(|(name, _), _| self.rules.get(name) == rules.get(name));
+        self.rules = rules;
+    }
+
+    /// Updates each rule that applies to the device with its latest values,
+    /// returning the alerts that started or stopped firing. `means` has the
+    /// windows of rules with a `mean`, which go by `time` so that they can be
+    /// kept across restarts.
+    pub fn observe(
+        &mut self,
+        device_id: &str,
+        values: &Values,
+        now: Instant,
+        time: SystemTime,
+        means: &mut Means,
+    ) -> Vec<Alert> {
+        let mut alerts = Vec::new();
+
+        for (name, rule) in &self.rules {
+            if !rule.applies_to(device_id) {
+                continue;
+            }
+            let Some(value) = values.get(rule.metric) else {
+                continue;
+            };
+
+            let state = self
+                .states
+                .entry((name.clone(), device_id.to_string()))
+                .or_insert_with(|| RuleState {
+                    crossed_since: None,
+                    firing: false,
+                    last_fired: None,
+                });
+
+            let value = match rule.mean {
+                Some(window) => {
+                    let rule_means = means.0.entry(name.clone()).or_default();
+                    if rule_means.metric != Some(rule.metric) {
+                        *rule_means = RuleMeans {
+                            metric: Some(rule.metric),
+                            ..Default::default()
+                        };
+                    }
+                    let mean = rule_means.devices.entry(device_id.to_string()).or_default();
+                    mean.push(window, time, value);
+                    match mean.mean(time) {
+                        Some(value) => value,
+                        None => continue,
+                    }
+                }
+                None => value,
+            };
+
+            let alert = |state| Alert {
+                rule: name.clone(),
+                metric: rule.metric,
+                state,
+                value,
+                threshold: rule.threshold(),
+            };
+
+            if state.firing {
+                if rule.is_cleared(value) {
+                    state.firing = false;
+                    state.crossed_since = None;
+                    alerts.push(alert(State::Resolved));
+                }
+                continue;
+            }
+
+            if !rule.is_crossed(value) {
+                state.crossed_since = None;
+                continue;
+            }
+
+            let since = *state.crossed_since.get_or_insert(now);
+            let held = now.saturating_duration_since(since) >= rule.duration.unwrap_or_default();
+            let cooled = match (state.last_fired, rule.cooldown) {
+                (Some(last), Some(cooldown)) => now.saturating_duration_since(last) >= cooldown,
+                _ => true,
+            };
+
+            if held && cooled {
+                state.firing = true;
+                state.last_fired = Some(now);
+                alerts.push(alert(State::Firing));
+            }
+        }
+
+        alerts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values(metric: Metric, value: f64) -> Values {
+        Values(BTreeMap::from([(metric, value)]))
+    }
+
+    fn states(alerts: Vec<Alert>) -> Vec<(String, State)> {
+        alerts
+            .into_iter()
+            .map(|alert| (alert.rule, alert.state))
+            .collect()
+    }
+
+    #[test]
+    fn test_duration_and_hysteresis() {
+        let rule: Rule = toml::from_str(
+            r#"
+metric = "co2"
+above = 1400
+for = "10m"
+hysteresis = 100
+"#,
+        )
+        .unwrap();
+        assert_eq!(rule.validate(), Ok(()));
+
+        let mut engine = Engine::new(BTreeMap::from([(String::from("co2"), rule)]));
+        let start = Instant::now();
+        let at = |mins: u64| start + Duration::from_secs(mins * 60);
+        let time = |mins: u64| SystemTime::UNIX_EPOCH + Duration::from_secs(mins * 60);
+        let mut means = Means::default();
+        let mut observe = |mins, value| {
+            states(engine.observe(
+                "11111",
+                &values(Metric::Co2, value),
+                at(mins),
+                time(mins),
+                &mut means,
+            ))
+        };
+
+        assert_eq!(observe(0, 1500.0), vec![]);
+        assert_eq!(observe(5, 1500.0), vec![]);
+        assert_eq!(
+            observe(10, 1450.0),
+            vec![(String::from("co2"), State::Firing)]
+        );
+        assert_eq!(observe(15, 1500.0), vec![]);
+        // Within the hysteresis, so still firing
+        assert_eq!(observe(20, 1350.0), vec![]);
+        assert_eq!(
+            observe(25, 1300.0),
+            vec![(String::from("co2"), State::Resolved)]
+        );
+
+        // Crossing again restarts the duration
+        assert_eq!(observe(30, 1500.0), vec![]);
+        assert_eq!(observe(35, 1200.0), vec![]);
+        assert_eq!(observe(40, 1500.0), vec![]);
+        assert_eq!(
+            observe(50, 1500.0),
+            vec![(String::from("co2"), State::Firing)]
+        );
+    }
+
+    #[test]
+    fn test_cooldown() {
+        let rule: Rule = toml::from_str(
+            r#"
+metric = "battery"
+below = 15
+cooldown = "1h"
+devices = ["11111"]
+"#,
+        )
+        .unwrap();
+
+        let mut engine = Engine::new(BTreeMap::from([(String::from("battery"), rule)]));
+        let start = Instant::now();
+        let at = |mins: u64| start + Duration::from_secs(mins * 60);
+        let time = |mins: u64| SystemTime::UNIX_EPOCH + Duration::from_secs(mins * 60);
+        let mut means = Means::default();
+
+        let low = values(Metric::Battery, 10.0);
+        let ok = values(Metric::Battery, 50.0);
+        assert_eq!(
+            states(engine.observe("22222", &low, at(0), time(0), &mut means)),
+            vec![]
+        );
+        assert_eq!(
+            states(engine.observe("11111", &low, at(0), time(0), &mut means)),
+            vec![(String::from("battery"), State::Firing)]
+        );
+        assert_eq!(
+            states(engine.observe("11111", &ok, at(10), time(10), &mut means)),
+            vec![(String::from("battery"), State::Resolved)]
+        );
+        assert_eq!(
+            states(engine.observe("11111", &low, at(20), time(20), &mut means)),
+            vec![]
+        );
+        assert_eq!(
+            states(engine.observe("11111", &low, at(60), time(60), &mut means)),
+            vec![(String::from("battery"), State::Firing)]
+        );
+    }
+
+    #[test]
+    fn test_mean() {
+        let rule: Rule = toml::from_str(
+            r#"
+metric = "radon"
+above = 150
+mean = "7d"
+"#,
+        )
+        .unwrap();
+
+        let mut engine = Engine::new(BTreeMap::from([(String::from("radon"), rule.clone())]));
+        let start = Instant::now();
+        let at = |hours: u64| start + Duration::from_secs(hours * 60 * 60);
+        let time = |hours: u64| SystemTime::UNIX_EPOCH + Duration::from_secs(hours * 60 * 60);
+        let mut means = Means::default();
+
+        // Nothing until there's a full week of readings
+        for hour in 0..24 * 7 {
+            let value = if hour < 48 { 400.0 } else { 100.0 };
+            let alerts = engine.observe(
+                "11111",
+                &values(Metric::Radon, value),
+                at(hour),
+                time(hour),
+                &mut means,
+            );
+            assert_eq!(alerts, vec![]);
+        }
+
+        let alerts = engine.observe(
+            "11111",
+            &values(Metric::Radon, 100.0),
+            at(24 * 7),
+            time(24 * 7),
+            &mut means,
+        );
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].state, State::Firing);
+        // 48 hours at 400 and 121 at 100, including both ends of the week
+        assert!((alerts[0].value - 31300.0 / 169.0).abs() < 1e-9);
+
+        // Changing a rule forgets its state, but not its windows
+        let mut changed = rule.clone();
+        changed.above = Some(160.0);
+        let rules = BTreeMap::from([(String::from("radon"), changed)]);
+        engine.set_rules(rules.clone());
+        assert!(engine.states.is_empty());
+        let alerts = engine.observe(
+            "11111",
+            &values(Metric::Radon, 100.0),
+            at(24 * 7 + 1),
+            time(24 * 7 + 1),
+            &mut means,
+        );
+        assert_eq!(states(alerts), vec![(String::from("radon"), State::Firing)]);
+
+        // Nor does restarting, with the windows from the state file
+        let json = serde_json::to_string(&means).unwrap();
+        let mut means: Means = serde_json::from_str(&json).unwrap();
+        let mut engine = Engine::new(rules.clone());
+        let alerts = engine.observe(
+            "11111",
+            &values(Metric::Radon, 100.0),
+            at(24 * 7 + 2),
+            time(24 * 7 + 2),
+            &mut means,
+        );
+        assert_eq!(states(alerts), vec![(String::from("radon"), State::Firing)]);
+
+        means.retain(&rules, ["22222"]);
+        assert!(means.0["radon"].devices.is_empty());
+        means.retain(&BTreeMap::new(), ["11111"]);
+        assert!(means.0.is_empty());
+    }
+
+    #[test]
+    fn test_validate() {
+        let rule = |source| toml::from_str::<Rule>(source).unwrap().validate();
+        assert!(rule("metric = \"co2\"").is_err());
+        assert!(rule("metric = \"co2\"\nabove = 1\nbelow = 0").is_err());
+        assert!(rule("metric = \"co2\"\nabove = 1\nhysteresis = -1").is_err());
+        assert!(toml::from_str::<Rule>("metric = \"co2\"\nabove = 1\nfor = \"soon\"").is_err());
+    }
+}
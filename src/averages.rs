@@ -0,0 +1,282 @@
+use crate::metric::{Metric, Values};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    time::{Duration, SystemTime},
+};
+
+/// The windows averages are kept over, with the suffix of their field names.
+/// The 24 hour, 7 day, and 30 day averages match those Aranet devices show.
+pub const WINDOWS: [(&str, Duration); 4] = [
+    ("1h", Duration::from_secs(60 * 60)),
+    ("24h", Duration::from_secs(24 * 60 * 60)),
+    ("7d", Duration::from_secs(7 * 24 * 60 * 60)),
+    ("30d", Duration::from_secs(30 * 24 * 60 * 60)),
+];
+
+/// Values are summed into this many buckets per window, which keeps memory
+/// and the state file small no matter how often readings arrive
+const BUCKETS: u64 = 120;
+
+/// Seconds since the Unix epoch, or 0 for a time before it
+pub fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Bucket {
+    /// Unix time in seconds
+    start: u64,
+    sum: f64,
+    count: u64,
+}
+
+/// Averages of one metric over each window
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Averages {
+    /// Unix time of the first value, in seconds
+    first: Option<u64>,
+    /// By window suffix
+    windows: BTreeMap<String, VecDeque<Bucket>>,
+}
+
+fn bucket_size(window: u64) -> u64 {
+    (window / BUCKETS).max(1)
+}
+
+/// Adds a value at `now`, in Unix seconds, dropping buckets that have left
+/// the window
+fn push_bucket(buckets: &mut VecDeque<Bucket>, window: u64, now: u64, value: f64) {
+    let size = bucket_size(window);
+    let start = now - now % size;
+
+    match buckets.back_mut() {
+        Some(bucket) if bucket.start == start => {
+            bucket.sum += value;
+            bucket.count += 1;
+        }
+        _ => buckets.push_back(Bucket {
+            start,
+            sum: value,
+            count: 1,
+        }),
+    }
+
+    let oldest = now.saturating_sub(window);
+    while buckets.front().is_some_and(|b| b.start + size <= oldest) {
+        buckets.pop_front();
+    }
+}
+
+/// The mean of the buckets in the window ending at `now`, once values have
+/// been pushed since `first` for at least the whole window
+fn bucket_mean(buckets: &VecDeque<Bucket>, window: u64, first: u64, now: u64) -> Option<f64> {
+    if now.saturating_sub(first) < window {
+        return None;
+    }
+
+    let size = bucket_size(window);
+    let oldest = now.saturating_sub(window);
+    let (sum, count) = buckets
+        .iter()
+        .filter(|b| b.start + size > oldest)
+        .fold((0.0, 0), |(sum, count), b| (sum + b.sum, count + b.count));
+    (count > 0).then(|| sum / count as f64)
+}
+
+impl Averages {
+    pub fn push(&mut self, time: SystemTime, value: f64) {
+        let now = unix_secs(time);
+        self.first = Some(self.first.map_or(now, |first| first.min(now)));
+
+        for (name, window) in WINDOWS {
+            let buckets = self.windows.entry(name.to_string()).or_default();
+            push_bucket(buckets, window.as_secs(), now, value);
+        }
+    }
+
+    /// The mean over the window, once values have been pushed for at least
+    /// the whole window
+    pub fn mean(&self, name: &str, now: SystemTime) -> Option<f64> {
+        let (_, window) = WINDOWS.iter().find(|(n, _)| *n == name)?;
+        bucket_mean(
+            self.windows.get(name)?,
+            window.as_secs(),
+            self.first?,
+            unix_secs(now),
+        )
+    }
+}
+
+/// The mean of one value over a window of any length, kept in buckets like
+/// `Averages` so that it's small enough for the state file
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Window {
+    /// The window's length in seconds, which the buckets are sized by
+    secs: u64,
+    /// Unix time of the first value, in seconds
+    first: Option<u64>,
+    buckets: VecDeque<Bucket>,
+}
+
+impl Window {
+    /// Adds a value, starting again if the window's length has changed
+    pub fn push(&mut self, window: Duration, time: SystemTime, value: f64) {
+        if self.secs != window.as_secs() {
+            *self = Window {
+                secs: window.as_secs(),
+                ..Default::default()
+            };
+        }
+
+        let now = unix_secs(time);
+        self.first = Some(self.first.map_or(now, |first| first.min(now)));
+        push_bucket(&mut self.buckets, self.secs, now, value);
+    }
+
+    /// The mean over the window, once values have been pushed for at least
+    /// the whole window
+    pub fn mean(&self, now: SystemTime) -> Option<f64> {
+        bucket_mean(&self.buckets, self.secs, self.first?, unix_secs(now))
+    }
+}
+
+/// Averages for each device, by ID, and metric. This is kept in the state
+/// file so that long windows survive restarts.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Tracker(pub BTreeMap<String, BTreeMap<Metric, Averages>>);
+
+impl Tracker {
+    /// Adds the device's values for each of the given metrics
+    pub fn push(&mut self, device_id: &str, metrics: &[Metric], values: &Values, time: SystemTime) {
+        let device = self.0.entry(device_id.to_string()).or_default();
+        for &metric in metrics {
+            if let Some(value) = values.get(metric) {
+                device.entry(metric).or_default().push(time, value);
+            }
+        }
+    }
+
+    /// The full windows for each of the device's metrics, as
+    /// `(metric, window suffix, mean)`
+    pub fn means(
+        &self,
+        device_id: &str,
+        metrics: &[Metric],
+        now: SystemTime,
+    ) -> Vec<(Metric, &'static str, f64)> {
+        let Some(device) = self.0.get(device_id) else {
+            return Vec::new();
+        };
+
+        metrics
+            .iter()
+            .filter_map(|metric| Some((*metric, device.get(metric)?)))
+            .flat_map(|(metric, averages)| {
+                WINDOWS
+                    .iter()
+                    .filter_map(move |(name, _)| Some((metric, *name, averages.mean(name, now)?)))
+            })
+            .collect()
+    }
+
+    /// Forgets devices that are no longer configured
+    pub fn retain<'a>(&mut self, device_ids: impl IntoIterator<Item = &'a str>) {
+        let ids = device_ids.into_iter().collect::<Vec<_>>();
+        self.0.retain(|id, _| ids.contains(&id.as_str()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_averages() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let at = |mins: u64| start + Duration::from_secs(mins * 60);
+
+        let mut averages = Averages::default();
+        for mins in 0..60 {
+            averages.push(at(mins), if mins < 30 { 100.0 } else { 200.0 });
+        }
+        assert_eq!(averages.mean("1h", at(59)), None);
+        assert_eq!(averages.mean("1h", at(60)), Some(150.0));
+        assert_eq!(averages.mean("24h", at(60)), None);
+        assert_eq!(averages.mean("2h", at(60)), None);
+
+        // The first half hour falls out of the 1 hour window
+        for mins in 60..90 {
+            averages.push(at(mins), 200.0);
+        }
+        assert_eq!(averages.mean("1h", at(90)), Some(200.0));
+
+        // Old buckets are dropped
+        assert_eq!(averages.windows["1h"].len(), 61);
+        averages.push(at(24 * 60), 300.0);
+        assert_eq!(averages.windows["1h"].len(), 1);
+        assert_eq!(averages.mean("1h", at(24 * 60)), Some(300.0));
+        let day = averages.mean("24h", at(24 * 60)).unwrap();
+        assert!((day - (30.0 * 100.0 + 60.0 * 200.0 + 300.0) / 91.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_tracker() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let values = Values(BTreeMap::from([
+            (Metric::Radon, 40.0),
+            (Metric::Co2, 800.0),
+        ]));
+
+        let mut tracker = Tracker::default();
+        tracker.push("11111", &[Metric::Radon], &values, start);
+        tracker.push(
+            "11111",
+            &[Metric::Radon],
+            &values,
+            start + Duration::from_secs(3600),
+        );
+        assert_eq!(
+            tracker.means(
+                "11111",
+                &[Metric::Radon, Metric::Co2],
+                start + Duration::from_secs(3600)
+            ),
+            vec![(Metric::Radon, "1h", 40.0)]
+        );
+
+        let json = serde_json::to_string(&tracker).unwrap();
+        let loaded: Tracker = serde_json::from_str(&json).unwrap();
+        assert_eq!(loaded, tracker);
+
+        tracker.retain(["22222"]);
+        assert!(tracker.0.is_empty());
+    }
+
+    #[test]
+    fn test_window() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let at = |secs: u64| start + Duration::from_secs(secs);
+        let minute = Duration::from_secs(60);
+
+        // Shorter than the number of buckets
+        let mut window = Window::default();
+        for secs in 0..60 {
+            window.push(minute, at(secs), secs as f64);
+        }
+        assert_eq!(window.mean(at(59)), None);
+        window.push(minute, at(60), 60.0);
+        assert_eq!(window.mean(at(60)), Some(30.0));
+
+        let json = serde_json::to_string(&window).unwrap();
+        let loaded: Window = serde_json::from_str(&json).unwrap();
+        assert_eq!(loaded, window);
+
+        // A new length starts again
+        window.push(minute * 2, at(61), 10.0);
+        assert_eq!(window.mean(at(61)), None);
+        assert_eq!(window.mean(at(181)), Some(10.0));
+    }
+}
@@ -0,0 +1,207 @@
+use crate::{averages::unix_secs, reading::Device};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    time::{Duration, SystemTime},
+};
+
+const DAY: f64 = 24.0 * 60.0 * 60.0;
+
+/// The observed slope is only trusted once the history covers this long
+const MIN_SPAN: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+/// ...and the battery has dropped at least this many percent, since it's
+/// only reported in whole percent
+const MIN_DROP: u8 = 2;
+/// History older than this is dropped, so the slope follows recent use
+const KEEP: Duration = Duration::from_secs(90 * 24 * 60 * 60);
+/// A rise of more than this is a battery swap, which starts a new history
+const SWAP: u8 = 10;
+
+/// How long a full battery lasts at the default interval, for each type of
+/// device. These are conservative compared to manufacturer claims, and only
+/// used until there's enough history to go by.
+pub fn nominal_life(device: Device) -> Duration {
+    let days = match device {
+        Device::Aranet4 | Device::Aranet2 | Device::AranetRadon => 2 * 365,
+        Device::AranetRadiation => 365,
+    };
+    Duration::from_secs(days * 24 * 60 * 60)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Forecast {
+    pub days: f64,
+    /// Whether this is from the device's own history rather than its type
+    pub observed: bool,
+}
+
+impl std::fmt::Display for Forecast {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let approx = if self.observed { "" } else { "~" };
+        write!(f, "{approx}{:.0}d", self.days)
+    }
+}
+
+/// The times a device's battery level changed, as unix seconds and percent
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct History(VecDeque<(u64, u8)>);
+
+impl History {
+    pub fn push(&mut self, time: SystemTime, battery: u8) {
+        let now = unix_secs(time);
+        match self.0.back() {
+            // The clock stepped backwards, like on a Pi without a real-time
+            // clock before NTP corrects it, so the reading can't be placed
+            Some(&(last, _)) if now < last => return,
+            Some(&(_, last)) if last == battery => return,
+            Some(&(_, last)) if battery > last.saturating_add(SWAP) => self.0.clear(),
+            _ => {}
+        }
+        self.0.push_back((now, battery));
+
+        while self.0.len() > 1
+            && self
+                .0
+                .front()
+                .is_some_and(|(time, _)| time + KEEP.as_secs() < now)
+        {
+            self.0.pop_front();
+        }
+    }
+
+    /// Percent per day, by least squares, once there's enough history. The
+    /// battery has been at `battery` since the last change, so that counts
+    /// too: without it, a long flat stretch wouldn't slow the rate down.
+    fn slope(&self, time: SystemTime, battery: u8) -> Option<f64> {
+        let now = unix_secs(time);
+        let mut points = self.0.iter().copied().collect::<Vec<_>>();
+        if points.last().is_some_and(|&(last, _)| now > last) {
+            points.push((now, battery));
+        }
+
+        let (first, _) = *points.first()?;
+        let min = points.iter().map(|(_, battery)| *battery).min()?;
+        let max = points.iter().map(|(_, battery)| *battery).max()?;
+        if now.saturating_sub(first) < MIN_SPAN.as_secs() || max - min < MIN_DROP {
+            return None;
+        }
+
+        let xy = points
+            .iter()
+            .map(|(time, battery)| (time.saturating_sub(first) as f64 / DAY, *battery as f64))
+            .collect::<Vec<_>>();
+        let n = xy.len() as f64;
+        let mean_x = xy.iter().map(|(x, _)| x).sum::<f64>() / n;
+        let mean_y = xy.iter().map(|(_, y)| y).sum::<f64>() / n;
+        let sxx = xy.iter().map(|(x, _)| (x - mean_x).powi(2)).sum::<f64>();
+        let sxy = xy
+            .iter()
+            .map(|(x, y)| (x - mean_x) * (y - mean_y))
+            .sum::<f64>();
+
+        let slope = sxy / sxx;
+        (slope < 0.0).then_some(slope)
+    }
+
+    /// Days until the battery is empty as of `time`, from the observed slope
+    /// if there's enough history, otherwise from the type of device
+    pub fn forecast(&self, device: Device, battery: u8, time: SystemTime) -> Forecast {
+        match self.slope(time, battery) {
+            Some(slope) => Forecast {
+                days: battery as f64 / -slope,
+                observed: true,
+            },
+            None => modelled(device, battery),
+        }
+    }
+}
+
+/// Days until the battery is empty, from the type of device alone
+pub fn modelled(device: Device, battery: u8) -> Forecast {
+    Forecast {
+        days: nominal_life(device).as_secs_f64() / DAY * battery as f64 / 100.0,
+        observed: false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_forecast() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let at = |days: u64| start + Duration::from_secs(days * 24 * 60 * 60);
+
+        let mut history = History::default();
+        history.push(at(0), 80);
+        history.push(at(1), 80);
+        history.push(at(5), 79);
+        assert_eq!(history.0.len(), 2);
+
+        // Not enough history yet, so going by a 2 year life
+        assert_eq!(
+            history.forecast(Device::Aranet4, 79, at(5)),
+            Forecast {
+                days: 730.0 * 0.79,
+                observed: false
+            }
+        );
+
+        // 1% every 5 days
+        history.push(at(10), 78);
+        history.push(at(15), 77);
+        let forecast = history.forecast(Device::Aranet4, 77, at(15));
+        assert!(forecast.observed);
+        assert!((forecast.days - 385.0).abs() < 1e-9);
+        assert_eq!(forecast.to_string(), "385d");
+
+        // A flat stretch since the last change slows the rate down
+        let flat = history.forecast(Device::Aranet4, 77, at(75));
+        assert!(flat.observed);
+        assert!(flat.days > 2.0 * forecast.days);
+        assert_eq!(modelled(Device::AranetRadiation, 50).to_string(), "~182d");
+
+        // A new battery starts again
+        history.push(at(20), 100);
+        assert_eq!(history.0.len(), 1);
+
+        // Old history is dropped
+        history.push(at(200), 90);
+        assert_eq!(history.0.len(), 1);
+    }
+
+    #[test]
+    fn test_clock_step() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let at = |days: u64| start + Duration::from_secs(days * 24 * 60 * 60);
+
+        let mut history = History::default();
+        history.push(at(10), 80);
+        history.push(SystemTime::UNIX_EPOCH, 79);
+        history.push(at(5), 78);
+        assert_eq!(history.0.len(), 1);
+        history.push(at(20), 78);
+        assert_eq!(history.0.len(), 2);
+
+        // Saved out of order, e.g. by an older version
+        let history = History(VecDeque::from([(unix_secs(at(20)), 80), (0, 70)]));
+        assert!(!history.forecast(Device::Aranet4, 70, at(20)).observed);
+    }
+
+    #[test]
+    fn test_min_span() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let at = |days: u64| start + Duration::from_secs(days * 24 * 60 * 60);
+
+        // Enough of a drop, but not yet over long enough
+        let mut history = History::default();
+        history.push(at(0), 80);
+        history.push(at(1), 78);
+        assert!(!history.forecast(Device::Aranet4, 78, at(1)).observed);
+
+        // Long enough counting the time since the last change
+        let forecast = history.forecast(Device::Aranet4, 78, at(8));
+        assert!(forecast.observed);
+    }
+}
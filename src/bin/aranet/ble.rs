@@ -0,0 +1,353 @@
+use anyhow::{Context, Result, anyhow, bail};
+use aranet::{
+    gatt,
+    reading::{Device, ManufacturerData, Reading},
+};
+use btleplug::api::{
+    BDAddr, Central, CentralEvent, CentralState, Characteristic, Manager as _, Peripheral as _,
+    ScanFilter, WriteType, bleuuid::uuid_from_u16,
+};
+use btleplug::platform::{Adapter, Manager, Peripheral};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    time::{Duration, Instant},
+};
+use uuid::Uuid;
+
+pub static MANUFACTURER_ID: u16 = 1794;
+pub static SERVICE_ID: u16 = 0xfce0;
+
+/// A manufacturer data advertisement along with the properties of the
+/// peripheral that sent it.
+#[derive(Debug, Clone)]
+pub struct Advertisement {
+    pub address: BDAddr,
+    pub local_name: Option<String>,
+    pub rssi: Option<i16>,
+    pub manufacturer_data: HashMap<u16, Vec<u8>>,
+}
+
+impl Advertisement {
+    /// The Aranet payload, if this advertisement included one
+    pub fn payload(&self) -> Option<&[u8]> {
+        self.manufacturer_data
+            .get(&MANUFACTURER_ID)
+            .map(|payload| payload.as_slice())
+    }
+}
+
+/// Returns the first powered-on Bluetooth adapter, already scanning for
+/// Aranet devices.
+pub async fn start_scan() -> Result<Adapter> {
+    let manager = Manager::new().await?;
+
+    let adapters = manager.adapters().await?;
+    let central = adapters
+        .into_iter()
+        .next()
+        .ok_or_else(|| anyhow!("No Bluetooth adapters found"))?;
+
+    let central_state = central.adapter_state().await?;
+    if central_state != CentralState::PoweredOn {
+        return Err(anyhow!("Bluetooth adapter is not powered on"));
+    }
+
+    let services = vec![uuid_from_u16(SERVICE_ID)];
+    central.start_scan(ScanFilter { services }).await?;
+
+    Ok(central)
+}
+
+/// Starts scanning and runs `f`, always stopping the scan afterwards
+pub async fn scanning<T>(f: impl AsyncFnOnce(&Adapter) -> Result<T>) -> Result<T> {
+    let central = start_scan().await?;
+    let result = f(&central).await;
+
+    if let Err(e) = central.stop_scan().await {
+        eprintln!("Error stopping scan: {e}");
+    }
+
+    result
+}
+
+/// Resolves a central event into an advertisement. Events other than
+/// manufacturer data advertisements produce `Ok(None)`.
+pub async fn advertisement(
+    central: &Adapter,
+    event: CentralEvent,
+) -> Result<Option<Advertisement>> {
+    let CentralEvent::ManufacturerDataAdvertisement {
+        id,
+        manufacturer_data,
+    } = event
+    else {
+        return Ok(None);
+    };
+
+    let peripheral = central
+        .peripheral(&id)
+        .await
+        .with_context(|| format!("Error getting peripheral for {id}"))?;
+
+    let properties = peripheral
+        .properties()
+        .await
+        .with_context(|| format!("Error getting properties for {id}"))?
+        .ok_or_else(|| anyhow!("No properties for {id}"))?;
+
+    Ok(Some(Advertisement {
+        address: properties.address,
+        local_name: properties.local_name,
+        rssi: properties.rssi,
+        manufacturer_data,
+    }))
+}
+
+/// Waits until the scan has seen each of the addresses, since devices can
+/// only be connected to once they've been seen, returning those seen before
+/// the timeout
+pub async fn wait_for(
+    central: &Adapter,
+    addresses: &[BDAddr],
+    timeout: Duration,
+) -> Result<HashSet<BDAddr>> {
+    let deadline = Instant::now() + timeout;
+    let mut seen = HashSet::new();
+    while seen.len() < addresses.len() && Instant::now() < deadline {
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        for peripheral in central.peripherals().await? {
+            let address = peripheral.address();
+            if addresses.contains(&address) {
+                seen.insert(address);
+            }
+        }
+    }
+    Ok(seen)
+}
+
+/// How many times to start a history download again when the device takes
+/// a measurement partway through
+const HISTORY_ATTEMPTS: usize = 3;
+
+/// How many more history chunks than values may be read for a parameter,
+/// such as chunks read twice, before giving up on the device
+const HISTORY_SLACK_READS: usize = 20;
+
+/// Finds a device that's been seen while scanning, along with its type
+pub async fn find_peripheral(central: &Adapter, address: BDAddr) -> Result<(Peripheral, Device)> {
+    let mut peripheral = None;
+    for p in central.peripherals().await? {
+        if p.address() == address {
+            peripheral = Some(p);
+            break;
+        }
+    }
+    let peripheral = peripheral.ok_or_else(|| anyhow!("{address} hasn't been seen yet"))?;
+
+    // Callers prefer the type in the advertisement, but the local name is
+    // all there is before one with manufacturer data arrives. Aranet4 is the
+    // most likely.
+    let device = peripheral
+        .properties()
+        .await?
+        .and_then(|properties| properties.local_name)
+        .as_deref()
+        .and_then(Device::from_local_name)
+        .unwrap_or(Device::Aranet4);
+
+    Ok((peripheral, device))
+}
+
+/// Connects to the peripheral and runs `f`, always disconnecting afterwards
+pub async fn connected<T>(
+    peripheral: &Peripheral,
+    f: impl AsyncFnOnce(&Peripheral) -> Result<T>,
+) -> Result<T> {
+    let address = peripheral.address();
+    peripheral
+        .connect()
+        .await
+        .with_context(|| format!("Error connecting to {address}"))?;
+
+    let result = async {
+        peripheral.discover_services().await?;
+        f(peripheral).await
+    }
+    .await;
+
+    if let Err(e) = peripheral.disconnect().await {
+        eprintln!("Error disconnecting from {address}: {e}");
+    }
+
+    result
+}
+
+fn characteristic(peripheral: &Peripheral, uuid: Uuid) -> Result<Characteristic> {
+    peripheral
+        .characteristics()
+        .into_iter()
+        .find(|c| c.uuid == uuid)
+        .ok_or_else(|| anyhow!("{} has no characteristic {uuid}", peripheral.address()))
+}
+
+pub async fn read(peripheral: &Peripheral, uuid: Uuid) -> Result<Vec<u8>> {
+    Ok(peripheral.read(&characteristic(peripheral, uuid)?).await?)
+}
+
+pub async fn read_u16(peripheral: &Peripheral, uuid: Uuid) -> Result<u16> {
+    gatt::parse_u16(&read(peripheral, uuid).await?).map_err(|e| anyhow!(e))
+}
+
+/// The header of the device's latest advertisement
+pub async fn advertised(peripheral: &Peripheral) -> Result<Option<ManufacturerData>> {
+    Ok(peripheral.properties().await?.and_then(|properties| {
+        let payload = properties.manufacturer_data.get(&MANUFACTURER_ID)?;
+        ManufacturerData::try_from(payload.as_slice()).ok()
+    }))
+}
+
+pub async fn write(peripheral: &Peripheral, uuid: Uuid, data: &[u8]) -> Result<()> {
+    let characteristic = characteristic(peripheral, uuid)?;
+    Ok(peripheral
+        .write(&characteristic, data, WriteType::WithResponse)
+        .await?)
+}
+
+/// Waits for the device to advertise a header that satisfies `f`, such as
+/// after changing a setting that's only visible in advertisements. Returns
+/// whether it did before the timeout.
+pub async fn wait_for_advertised(
+    central: &Adapter,
+    address: BDAddr,
+    timeout: Duration,
+    f: impl Fn(&ManufacturerData) -> bool,
+) -> Result<bool> {
+    let (peripheral, _) = find_peripheral(central, address).await?;
+    let deadline = Instant::now() + timeout;
+    while Instant::now() < deadline {
+        let advertised = advertised(&peripheral).await?;
+        if advertised.as_ref().is_some_and(&f) {
+            return Ok(true);
+        }
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+    Ok(false)
+}
+
+/// Connects to a device that's been seen while scanning, reads its current
+/// readings, and disconnects
+pub async fn read_current(central: &Adapter, address: BDAddr) -> Result<Reading> {
+    let (peripheral, device) = find_peripheral(central, address).await?;
+    let uuid = gatt::current_readings(device).map_err(|e| anyhow!(e))?;
+
+    connected(&peripheral, async |peripheral| {
+        gatt::parse_current_readings(device, &read(peripheral, uuid).await?).map_err(|e| anyhow!(e))
+    })
+    .await
+}
+
+/// Connects to a device that's been seen while scanning and downloads the
+/// history it has kept, going back as far as `since` if given
+pub async fn read_history(
+    central: &Adapter,
+    address: BDAddr,
+    since: Option<Duration>,
+) -> Result<Vec<Reading>> {
+    let (peripheral, device) = find_peripheral(central, address).await?;
+    let params = gatt::history_params(device).map_err(|e| anyhow!(e))?;
+    let current = gatt::current_readings(device).map_err(|e| anyhow!(e))?;
+
+    connected(&peripheral, async |peripheral| {
+        let battery = gatt::parse_current_readings(device, &read(peripheral, current).await?)
+            .map_err(|e| anyhow!(e))?
+            .battery;
+
+        for _ in 0..HISTORY_ATTEMPTS {
+            match download(peripheral, device, params, since).await? {
+                Some(history) => return history.readings(battery).map_err(|e| anyhow!(e)),
+                None => {
+                    eprintln!("{address} took a measurement during the download, starting again")
+                }
+            }
+        }
+
+        Err(anyhow!(
+            "{address} kept taking measurements during the download"
+        ))
+    })
+    .await
+}
+
+/// Downloads each parameter's history, or `None` if the device took a
+/// measurement partway through, which shifts the history
+async fn download(
+    peripheral: &Peripheral,
+    device: Device,
+    params: &[gatt::Param],
+    since: Option<Duration>,
+) -> Result<Option<gatt::History>> {
+    let total = read_u16(peripheral, gatt::TOTAL_READINGS).await?;
+    let interval = read_u16(peripheral, gatt::INTERVAL).await?;
+    let mut ago = read_u16(peripheral, gatt::SINCE_UPDATE).await?;
+    if interval == 0 {
+        bail!("{} reported an interval of 0s", peripheral.address());
+    }
+
+    let wanted = since.map_or(total as u64, |since| since.as_secs() / interval as u64);
+    let start = (total as u64).saturating_sub(wanted) as u16 + 1;
+
+    let command = characteristic(peripheral, gatt::COMMAND)?;
+    let history = characteristic(peripheral, gatt::HISTORY)?;
+
+    let mut values = BTreeMap::new();
+    for param in params {
+        peripheral
+            .write(
+                &command,
+                &gatt::history_command(*param, start),
+                WriteType::WithResponse,
+            )
+            .await?;
+
+        // Every chunk that isn't a repeat has at least one value
+        let mut reads = (total as usize + 1).saturating_sub(start as usize) + HISTORY_SLACK_READS;
+        let mut param_values: Vec<u16> = Vec::new();
+        loop {
+            if reads == 0 {
+                bail!(
+                    "{} kept sending history chunks that didn't follow on",
+                    peripheral.address()
+                );
+            }
+            reads -= 1;
+
+            let raw = peripheral.read(&history).await?;
+            let chunk = gatt::parse_history_chunk(*param, &raw).map_err(|e| anyhow!(e))?;
+            if chunk.total != total || chunk.ago < ago {
+                return Ok(None);
+            }
+            ago = chunk.ago;
+
+            if chunk.values.is_empty() {
+                break;
+            }
+            // The same chunk can be read twice if the next isn't ready yet
+            if chunk.start as usize == start as usize + param_values.len() {
+                param_values.extend(chunk.values);
+            }
+            if start as usize + param_values.len() > total as usize {
+                break;
+            }
+        }
+        values.insert(*param, param_values);
+    }
+
+    Ok(Some(gatt::History {
+        device,
+        interval,
+        total,
+        ago,
+        start,
+        values,
+    }))
+}
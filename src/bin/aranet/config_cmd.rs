@@ -0,0 +1,161 @@
+use crate::{find, write_atomic};
+use anyhow::{Context, Result, anyhow, bail};
+use aranet::config;
+use btleplug::api::BDAddr;
+use std::{collections::HashSet, io::Write, path::Path, str::FromStr, time::Duration};
+use toml_edit::{DocumentMut, InlineTable, Item, Value};
+
+/// Reads the config file, treating a missing file as empty so that the first
+/// device can be added to a new config
+async fn read(path: &Path) -> Result<String> {
+    match tokio::fs::read_to_string(path).await {
+        Ok(content) => Ok(content),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(String::new()),
+        Err(e) => Err(e).with_context(|| format!("Failed to read config file {}", path.display())),
+    }
+}
+
+fn prompt(message: &str) -> Result<String> {
+    print!("{message}: ");
+    std::io::stdout().flush()?;
+
+    let mut line = String::new();
+    std::io::stdin().read_line(&mut line)?;
+    Ok(line.trim().to_string())
+}
+
+/// Scans for devices that aren't yet configured and asks which one to add
+async fn pick(
+    config: &config::Config,
+    name: Option<String>,
+    id: Option<String>,
+    duration: Duration,
+) -> Result<(String, BDAddr, String)> {
+    let configured = config
+        .devices
+        .values()
+        .filter_map(|device| device.bdaddr().ok())
+        .collect::<HashSet<_>>();
+
+    eprintln!("Scanning for {}s...", duration.as_secs());
+    let candidates = find::discover(duration)
+        .await?
+        .into_iter()
+        .filter(|d| !configured.contains(&d.address))
+        .collect::<Vec<_>>();
+
+    if candidates.is_empty() {
+        bail!("No new Aranet devices found");
+    }
+
+    for (i, d) in candidates.iter().enumerate() {
+        println!(
+            "{:>3}. {}  {}  {}",
+            i + 1,
+            d.address,
+            d.device
+                .map_or_else(|| String::from("-"), |d| d.to_string()),
+            d.local_name.as_deref().unwrap_or("(unnamed)"),
+        );
+    }
+
+    let choice = prompt("Device to add")?;
+    let discovered = choice
+        .parse::<usize>()
+        .ok()
+        .and_then(|i| candidates.get(i.checked_sub(1)?))
+        .ok_or_else(|| anyhow!("Invalid choice: {choice}"))?;
+
+    let name = match name {
+        Some(name) => name,
+        None => {
+            let default = discovered.suggested_name();
+            let name = prompt(&format!("Name [{default}]"))?;
+            if name.is_empty() { default } else { name }
+        }
+    };
+
+    let id = id.unwrap_or_else(|| discovered.suggested_id());
+
+    Ok((id, discovered.address, name))
+}
+
+pub async fn add(
+    path: &Path,
+    address: Option<String>,
+    name: Option<String>,
+    id: Option<String>,
+    duration: Duration,
+) -> Result<()> {
+    let content = read(path).await?;
+    let mut document = content
+        .parse::<DocumentMut>()
+        .with_context(|| format!("Failed to parse config file {}", path.display()))?;
+
+    let config = if content.trim().is_empty() {
+        config::Config::default()
+    } else {
+        config::Config::try_from(content.as_ref())
+            .with_context(|| format!("Failed to load config file {}", path.display()))?
+    };
+
+    let (id, address, name) = match address {
+        Some(address) => {
+            let address =
+                BDAddr::from_str(&address).with_context(|| format!("Invalid address {address}"))?;
+            let name = name.ok_or_else(|| anyhow!("--name is required with --address"))?;
+            let id = id.unwrap_or_else(|| address.to_string_no_delim());
+            (id, address, name)
+        }
+        None => pick(&config, name, id, duration).await?,
+    };
+
+    config
+        .check_new_device(&id, address, &name)
+        .map_err(|e| anyhow!(e))?;
+
+    let devices = document
+        .entry("devices")
+        .or_insert(toml_edit::table())
+        .as_table_like_mut()
+        .ok_or_else(|| anyhow!("devices in {} is not a table", path.display()))?;
+
+    let mut device = InlineTable::new();
+    device.insert("address", Value::from(address.to_string()));
+    device.insert("name", Value::from(name.as_str()));
+    devices.insert(&id, Item::Value(Value::InlineTable(device)));
+
+    write_atomic(path, &document.to_string()).await?;
+
+    println!("Added {name} ({address}) to {}", path.display());
+    Ok(())
+}
+
+pub async fn check(path: &Path, scan: Option<Duration>) -> Result<()> {
+    let content = tokio::fs::read_to_string(path)
+        .await
+        .with_context(|| format!("Failed to read config file {}", path.display()))?;
+
+    let seen = match scan {
+        Some(duration) => {
+            eprintln!("Scanning for {}s...", duration.as_secs());
+            let discovered = find::discover(duration).await?;
+            Some(discovered.into_iter().map(|d| d.address).collect())
+        }
+        None => None,
+    };
+
+    let diagnostics = config::Config::check(&content, seen.as_ref());
+    for diagnostic in &diagnostics {
+        println!("{}:{diagnostic}", path.display());
+    }
+
+    match diagnostics.len() {
+        0 => {
+            println!("{}: OK", path.display());
+            Ok(())
+        }
+        1 => bail!("Found 1 problem in {}", path.display()),
+        n => bail!("Found {n} problems in {}", path.display()),
+    }
+}
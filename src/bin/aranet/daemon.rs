@@ -0,0 +1,886 @@
+use crate::{ble, devices, history, load_config, state};
+use anyhow::{Result, anyhow};
+use aranet::{
+    alert, averages, config, filter, gatt,
+    metric::{Metric, Values},
+    occupancy,
+    output::{Field, Point},
+    reading::{self, Reading},
+    stale::{self, Transition},
+    ventilation,
+};
+use btleplug::{
+    api::{BDAddr, Central, CentralEvent},
+    platform::Adapter,
+};
+use futures::stream::StreamExt;
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use sd_notify::NotifyState;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    io::Write,
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime},
+};
+use tokio::{
+    signal::unix::{SignalKind, signal},
+    sync::mpsc,
+    time::error::Elapsed,
+};
+
+/// Editors often write a file in several steps, so changes are only acted on
+/// once the file has been quiet for this long
+const DEBOUNCE: Duration = Duration::from_millis(250);
+
+/// How often the status shown by `systemctl status` is updated
+const STATUS_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How often the state file is saved, besides when stopping
+const SAVE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// How often to check whether any devices are due to be polled over GATT
+const POLL_CHECK: Duration = Duration::from_secs(5);
+
+/// How long a GATT poll can take before it's given up on
+const GATT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long to wait after a failed GATT poll before trying again
+const GATT_RETRY: Duration = Duration::from_secs(60);
+
+/// How long downloading a device's missed readings can take before it's
+/// given up on
+const BACKFILL_TIMEOUT: Duration = Duration::from_secs(2 * 60);
+
+/// How long after a device's next measurement is due to poll it over GATT,
+/// so that the measurement has been taken
+const GATT_MARGIN: Duration = Duration::from_secs(5);
+
+/// Work done in its own task, so that a slow device doesn't hold up signals,
+/// advertisements, and the watchdog
+enum Done {
+    Poll {
+        address: BDAddr,
+        id: String,
+        name: String,
+        result: Result<Result<Reading>, Elapsed>,
+    },
+    Backfill {
+        id: String,
+        /// The time of the last reading output before the gap
+        last: SystemTime,
+        /// The reading that ended the gap, which is held with the rest
+        reading: Reading,
+        result: Result<Result<Vec<Reading>>, Elapsed>,
+    },
+}
+
+struct Daemon {
+    config_file: PathBuf,
+    devices: HashMap<BDAddr, config::Device>,
+    warned: HashSet<BDAddr>,
+    /// The last reading printed for each device, by device ID
+    last_reading: HashMap<String, Reading>,
+    stale: stale::Tracker,
+    /// When each device read over GATT is next due to be polled, by device
+    /// ID. Devices without an entry are due now.
+    polls: HashMap<String, Instant>,
+    /// Devices with a GATT poll in flight, by device ID
+    polling: HashSet<String>,
+    /// Devices with a backfill in flight, by device ID, with their live
+    /// readings held until it finishes so that the gap is output first
+    held: HashMap<String, Vec<(Reading, Option<i16>)>>,
+    /// Where tasks send what they've done
+    done: mpsc::UnboundedSender<Done>,
+    filters: filter::Filters,
+    alerts: alert::Engine,
+    ventilation: Option<ventilation::Estimator>,
+    state: state::State,
+    state_file: Option<PathBuf>,
+    saved: Instant,
+}
+
+/// What the daemon needs from the config
+struct Loaded {
+    devices: HashMap<BDAddr, config::Device>,
+    alerts: BTreeMap<String, alert::Rule>,
+    ventilation: Option<ventilation::Settings>,
+}
+
+/// Splits a loaded config into what the daemon needs, checking the parts
+/// that loading doesn't
+fn split(mut config: config::Config) -> Result<Loaded> {
+    config.validate_alerts().map_err(|e| anyhow!(e))?;
+    config.validate_ventilation().map_err(|e| anyhow!(e))?;
+    let alerts = std::mem::take(&mut config.alerts);
+    let ventilation = config.ventilation.take();
+    Ok(Loaded {
+        devices: devices(config)?,
+        alerts,
+        ventilation,
+    })
+}
+
+impl Daemon {
+    async fn handle(&mut self, central: &Adapter, event: CentralEvent) {
+        let advertisement = match ble::advertisement(central, event).await {
+            Ok(Some(advertisement)) => advertisement,
+            Ok(None) => return,
+            Err(e) => {
+                eprintln!("{e:?}");
+                return;
+            }
+        };
+
+        follow_device(&mut self.devices, &mut self.warned, &advertisement);
+
+        let Some(device) = self.devices.get(&advertisement.address) else {
+            return;
+        };
+
+        // Readings for these come from polling instead
+        if device.gatt() {
+            return;
+        }
+
+        let payload = match advertisement.payload() {
+            Some(payload) => payload,
+            None => {
+                eprintln!(
+                    "No manufacturer data from {}: {:?}",
+                    device.name, advertisement.manufacturer_data
+                );
+                return;
+            }
+        };
+
+        let reading = match Reading::try_from(payload) {
+            Ok(r) => r,
+            Err(e) => {
+                eprintln!(
+                    "Failed to parse payload from {}: {:?} {:?}",
+                    device.name, e, payload
+                );
+                return;
+            }
+        };
+
+        self.record(central, advertisement.address, reading, advertisement.rssi);
+    }
+
+    /// Starts reading each device that's due to be polled over GATT, unless
+    /// it's still being read
+    fn poll(&mut self, central: &Adapter) {
+        let now = Instant::now();
+        for (&address, device) in &self.devices {
+            if !device.gatt()
+                || self.polling.contains(&device.id)
+                || self.polls.get(&device.id).is_some_and(|next| *next > now)
+            {
+                continue;
+            }
+
+            self.polling.insert(device.id.clone());
+            let central = central.clone();
+            let id = device.id.clone();
+            let name = device.name.clone();
+            let done = self.done.clone();
+            tokio::spawn(async move {
+                let result =
+                    tokio::time::timeout(GATT_TIMEOUT, ble::read_current(&central, address)).await;
+                let _ = done.send(Done::Poll {
+                    address,
+                    id,
+                    name,
+                    result,
+                });
+            });
+        }
+    }
+
+    /// Handles the end of a GATT poll
+    fn polled(
+        &mut self,
+        central: &Adapter,
+        address: BDAddr,
+        id: String,
+        name: &str,
+        result: Result<Result<Reading>, Elapsed>,
+    ) {
+        self.polling.remove(&id);
+        let next = match result {
+            Ok(Ok(reading)) => {
+                // Just after the device's next measurement
+                let next =
+                    reading.instant + Duration::from_secs(reading.interval.into()) + GATT_MARGIN;
+                self.record(central, address, reading, None);
+                next.max(Instant::now() + POLL_CHECK)
+            }
+            Ok(Err(e)) => {
+                eprintln!("Failed to poll {name} ({id}) over GATT: {e:?}");
+                Instant::now() + GATT_RETRY
+            }
+            Err(_) => {
+                eprintln!(
+                    "Timed out polling {name} ({id}) over GATT after {}s",
+                    GATT_TIMEOUT.as_secs()
+                );
+                Instant::now() + GATT_RETRY
+            }
+        };
+        self.polls.insert(id, next);
+    }
+
+    fn finish(&mut self, central: &Adapter, done: Done) {
+        match done {
+            Done::Poll {
+                address,
+                id,
+                name,
+                result,
+            } => self.polled(central, address, id, &name, result),
+            Done::Backfill {
+                id,
+                last,
+                reading,
+                result,
+            } => self.backfilled(id, last, &reading, result),
+        }
+    }
+
+    /// Outputs a reading from a device, along with everything derived from it
+    fn record(&mut self, central: &Adapter, address: BDAddr, reading: Reading, rssi: Option<i16>) {
+        self.backfill(central, address, &reading);
+
+        let Some(device) = self.devices.get(&address) else {
+            return;
+        };
+
+        let interval = Duration::from_secs(reading.interval.into());
+        if let Some(Transition::Up(silent)) = self.stale.seen(&device.id, interval, reading.instant)
+        {
+            eprintln!(
+                "Device {} ({}) is back after {}s",
+                device.name,
+                device.id,
+                silent.as_secs()
+            );
+            let mut point =
+                Point::for_device("aranet_device", device, Some(reading.device), reading.time);
+            point.field("up", Field::Integer(1));
+            println!("{point}");
+        }
+
+        if let Some(held) = self.held.get_mut(&device.id) {
+            held.push((reading, rssi));
+            return;
+        }
+        self.output(address, reading, rssi);
+    }
+
+    /// Outputs a reading that isn't held for a backfill
+    fn output(&mut self, address: BDAddr, reading: Reading, rssi: Option<i16>) {
+        let Some(device) = self.devices.get(&address) else {
+            return;
+        };
+
+        if let Some(last) = self.last_reading.get(&device.id)
+            && last.is_repeat_reading(&reading)
+        {
+            return;
+        }
+
+        let values = device.values(&reading);
+        let mut point = Point::for_device("aranet", device, Some(reading.device), reading.time);
+        point.reading_fields(&reading, &values);
+
+        let filtered = self
+            .filters
+            .apply(&device.id, &device.filters, &values, reading.instant);
+        point.filtered_fields(&filtered, &reading);
+
+        if device.comfort()
+            && let Some(comfort) = values.comfort()
+        {
+            point.comfort_fields(&comfort);
+        }
+
+        if let Some(occupancy) = self.occupancy(device, &reading, &values) {
+            point.field("occupancy", Field::Fixed(occupancy, 1));
+        }
+
+        if let Some(radiation) = &reading.radiation {
+            let dose = self.state.doses.entry(device.id.clone()).or_default();
+            if dose.update(radiation) {
+                eprintln!(
+                    "Device {} ({}) restarted its dose measurement",
+                    device.name, device.id
+                );
+            }
+            point.field("radiation_lifetime", Field::Float(dose.lifetime()));
+        }
+
+        let metrics = device.averages(reading.device);
+        self.state
+            .averages
+            .push(&device.id, &metrics, &values, reading.time);
+        let means = self
+            .state
+            .averages
+            .means(&device.id, &metrics, reading.time);
+        point.average_fields(&means, &reading);
+
+        let battery = self.state.batteries.entry(device.id.clone()).or_default();
+        battery.push(reading.time, reading.battery);
+        let forecast = battery.forecast(reading.device, reading.battery, reading.time);
+        point.field("battery_days", Field::Integer(forecast.days.round() as i64));
+
+        if let Some(rssi) = rssi {
+            point.field("rssi", Field::Integer(rssi.into()));
+        }
+
+        println!("{point}");
+
+        // Alerts go by the filtered values, so that a spike doesn't fire them
+        let mut alerting = values.clone();
+        alerting.0.extend(&filtered.0);
+        for alert in self.alerts.observe(
+            &device.id,
+            &alerting,
+            reading.instant,
+            reading.time,
+            &mut self.state.alert_means,
+        ) {
+            eprintln!("{alert} for {} ({})", device.name, device.id);
+            let mut point =
+                Point::for_device("aranet_alert", device, Some(reading.device), reading.time);
+            point.tag("alert", &alert.rule);
+            point.tag("metric", alert.metric.name());
+            point.field("state", Field::String(alert.state.name().to_string()));
+            point.field("value", Field::Float(alert.value));
+            point.field("threshold", Field::Float(alert.threshold));
+            println!("{point}");
+        }
+
+        if let Some(estimate) = self
+            .ventilation
+            .as_mut()
+            .and_then(|ventilation| ventilation.observe(&device.id, &values, reading.instant))
+        {
+            let mut point = Point::for_device(
+                "aranet_ventilation",
+                device,
+                Some(reading.device),
+                reading.time,
+            );
+            point.field("ach", Field::Fixed(estimate.ach, 2));
+            point.field("r_squared", Field::Fixed(estimate.r_squared, 3));
+            point.field(
+                "duration",
+                Field::Integer(estimate.duration.as_secs() as i64),
+            );
+            point.field(
+                "start_co2",
+                Field::Integer(estimate.start_co2.round() as i64),
+            );
+            point.field("end_co2", Field::Integer(estimate.end_co2.round() as i64));
+            point.field(
+                "outdoor_co2",
+                Field::Integer(estimate.outdoor_co2.round() as i64),
+            );
+            println!("{point}");
+        }
+        self.state
+            .last_times
+            .insert(device.id.clone(), averages::unix_secs(reading.time));
+        self.last_reading.insert(device.id.clone(), reading);
+    }
+
+    /// Starts downloading the readings a device missed since its last
+    /// output reading, if it's been silent for long enough to go stale, such
+    /// as while the service was down
+    fn backfill(&mut self, central: &Adapter, address: BDAddr, reading: &Reading) {
+        let Some(device) = self.devices.get(&address) else {
+            return;
+        };
+        if !device.backfill()
+            || gatt::history_params(reading.device).is_err()
+            || self.held.contains_key(&device.id)
+        {
+            return;
+        }
+        let Some(last) = self.state.last_times.get(&device.id) else {
+            return;
+        };
+
+        let last = SystemTime::UNIX_EPOCH + Duration::from_secs(*last);
+        let interval = Duration::from_secs(reading.interval.into());
+        let Ok(gap) = reading.time.duration_since(last) else {
+            return;
+        };
+        if gap.as_secs_f64() <= interval.as_secs_f64() * device.stale_after() {
+            return;
+        }
+
+        eprintln!(
+            "Backfilling {}s of readings from {} ({})",
+            gap.as_secs(),
+            device.name,
+            device.id
+        );
+        self.held.insert(device.id.clone(), Vec::new());
+        let central = central.clone();
+        let id = device.id.clone();
+        let reading = reading.clone();
+        let done = self.done.clone();
+        tokio::spawn(async move {
+            let result = tokio::time::timeout(
+                BACKFILL_TIMEOUT,
+                ble::read_history(&central, address, Some(gap + interval)),
+            )
+            .await;
+            let _ = done.send(Done::Backfill {
+                id,
+                last,
+                reading,
+                result,
+            });
+        });
+    }
+
+    /// Outputs the readings a device missed, once they've been downloaded,
+    /// followed by the live readings held meanwhile. The missed readings are
+    /// output as they were measured, without anything derived from them,
+    /// since filters, averages, and alerts would see them out of order.
+    fn backfilled(
+        &mut self,
+        id: String,
+        last: SystemTime,
+        reading: &Reading,
+        result: Result<Result<Vec<Reading>>, Elapsed>,
+    ) {
+        let held = self.held.remove(&id).unwrap_or_default();
+        let Some((&address, device)) = self.devices.iter().find(|(_, device)| device.id == id)
+        else {
+            return;
+        };
+
+        let readings = match result {
+            Ok(Ok(readings)) => Some(readings),
+            Ok(Err(e)) => {
+                eprintln!("Failed to backfill {} ({}): {e:?}", device.name, device.id);
+                None
+            }
+            Err(_) => {
+                eprintln!(
+                    "Timed out backfilling {} ({}) after {}s",
+                    device.name,
+                    device.id,
+                    BACKFILL_TIMEOUT.as_secs()
+                );
+                None
+            }
+        };
+
+        // The history also has the last reading that was output and the one
+        // that ended the gap
+        if let Some(readings) = readings {
+            let half = Duration::from_secs(reading.interval.into()) / 2;
+            let missed = readings
+                .into_iter()
+                .filter(|missed| missed.time > last + half && missed.time + half < reading.time)
+                .collect::<Vec<_>>();
+            eprintln!(
+                "Backfilled {} readings from {} ({})",
+                missed.len(),
+                device.name,
+                device.id
+            );
+            history::print(device, &missed, history::Format::Influx);
+        }
+
+        for (reading, rssi) in held {
+            self.output(address, reading, rssi);
+        }
+    }
+
+    /// Estimates the people in the device's room from the rise in CO₂ since
+    /// its last reading, if its room volume is configured
+    fn occupancy(
+        &self,
+        device: &config::Device,
+        reading: &Reading,
+        values: &Values,
+    ) -> Option<f64> {
+        let room = device.room()?;
+        let last = self.last_reading.get(&device.id)?;
+
+        // A rate over missed readings would hide any change in between
+        let interval = Duration::from_secs(reading.interval.into());
+        if reading.instant.saturating_duration_since(last.instant) > interval * 2 {
+            return None;
+        }
+
+        let ventilation = self.ventilation.as_ref().and_then(|ventilation| {
+            Some(occupancy::Ventilation {
+                ach: ventilation.ach(&device.id)?,
+                outdoor_co2: ventilation.outdoor_co2(),
+            })
+        });
+
+        room.occupancy(
+            (last.instant, device.values(last).get(Metric::Co2)?),
+            (reading.instant, values.get(Metric::Co2)?),
+            ventilation,
+        )
+    }
+
+    async fn save(&mut self) {
+        let Some(path) = &self.state_file else {
+            return;
+        };
+        if let Err(e) = state::save(path, &self.state).await {
+            eprintln!("{e:?}");
+        }
+        self.saved = Instant::now();
+    }
+
+    /// Reports devices that have gone too long without a reading
+    fn check_stale(&mut self) {
+        let transitions = self.stale.check(
+            self.devices
+                .values()
+                .map(|device| (device.id.as_str(), device.stale_after())),
+            Instant::now(),
+        );
+
+        for (id, transition) in transitions {
+            let Transition::Down(silent) = transition else {
+                continue;
+            };
+            let Some(device) = self.devices.values().find(|device| device.id == id) else {
+                continue;
+            };
+
+            eprintln!(
+                "Device {} ({id}) hasn't sent a reading for {}s",
+                device.name,
+                silent.as_secs()
+            );
+            let kind = self.last_reading.get(&id).map(|reading| reading.device);
+            let mut point = Point::for_device("aranet_device", device, kind, SystemTime::now());
+            point.field("up", Field::Integer(0));
+            println!("{point}");
+        }
+    }
+
+    /// Devices that have sent a reading and aren't stale
+    fn reporting(&self) -> usize {
+        self.devices
+            .values()
+            .filter(|device| self.stale.is_up(&device.id))
+            .count()
+    }
+
+    fn status(&self) -> String {
+        format!(
+            "{}/{} devices reporting",
+            self.reporting(),
+            self.devices.len()
+        )
+    }
+
+    /// Loads the config file again, keeping the current config if the new one
+    /// has any problems
+    async fn reload(&mut self) {
+        let loaded = match load_config(&self.config_file).await.and_then(split) {
+            Ok(loaded) => loaded,
+            Err(e) => {
+                eprintln!(
+                    "Keeping the current config, failed to reload {}: {e:?}",
+                    self.config_file.display()
+                );
+                return;
+            }
+        };
+
+        if self.alerts.rules() != &loaded.alerts {
+            eprintln!("Reloaded {} alert rule(s)", loaded.alerts.len());
+            self.alerts.set_rules(loaded.alerts);
+            self.state.alert_means.retain(
+                self.alerts.rules(),
+                self.devices.values().map(|device| device.id.as_str()),
+            );
+        }
+
+        let current = self.ventilation.as_ref().map(|v| v.settings());
+        if current != loaded.ventilation.as_ref() {
+            eprintln!("Reloaded ventilation settings");
+            self.ventilation = loaded.ventilation.map(ventilation::Estimator::new);
+        }
+
+        self.swap(loaded.devices);
+    }
+
+    fn swap(&mut self, mut devices: HashMap<BDAddr, config::Device>) {
+        let changes = config::changes(self.devices.values(), devices.values());
+        if changes.is_empty() {
+            return;
+        }
+
+        let mut forget = HashSet::new();
+        for change in &changes {
+            eprintln!("{change}");
+            if let config::Change::Removed { id, .. } | config::Change::Moved { id, .. } = change {
+                forget.insert(id.as_str());
+            }
+        }
+
+        // Devices that were followed to a new address stay there, unless
+        // their configured address changed
+        for (&address, device) in &self.devices {
+            if forget.contains(device.id.as_str()) || device.bdaddr() == Ok(address) {
+                continue;
+            }
+            let old = devices
+                .iter()
+                .find(|(_, new)| new.id == device.id)
+                .map(|(old, _)| *old);
+            if let Some(old) = old
+                && let Some(new) = devices.remove(&old)
+            {
+                devices.insert(address, new);
+            }
+        }
+
+        self.last_reading
+            .retain(|id, _| !forget.contains(id.as_str()));
+        self.state
+            .averages
+            .retain(devices.values().map(|device| device.id.as_str()));
+        self.filters
+            .retain(devices.values().map(|device| device.id.as_str()));
+        self.polls
+            .retain(|id, _| devices.values().any(|device| &device.id == id));
+        self.state
+            .last_times
+            .retain(|id, _| devices.values().any(|device| &device.id == id));
+        self.state
+            .batteries
+            .retain(|id, _| devices.values().any(|device| &device.id == id));
+        self.state.alert_means.retain(
+            self.alerts.rules(),
+            devices.values().map(|device| device.id.as_str()),
+        );
+        self.devices = devices;
+        self.warned.clear();
+
+        eprintln!(
+            "Reloaded {} with {} device(s)",
+            self.config_file.display(),
+            self.devices.len()
+        );
+    }
+}
+
+/// Aranet devices advertise their ID in their name, which lets us follow a
+/// configured device to a new address, such as after a battery swap. Also
+/// warns once about any device whose advertised ID doesn't match its key.
+fn follow_device(
+    devices: &mut HashMap<BDAddr, config::Device>,
+    warned: &mut HashSet<BDAddr>,
+    advertisement: &ble::Advertisement,
+) {
+    let address = advertisement.address;
+    let Some(id) = advertisement
+        .local_name
+        .as_deref()
+        .and_then(reading::id_from_local_name)
+    else {
+        return;
+    };
+
+    if !devices.contains_key(&address) {
+        let old = devices
+            .iter()
+            .find(|(_, device)| device.id.eq_ignore_ascii_case(id))
+            .map(|(old, _)| *old);
+
+        if let Some(old) = old
+            && let Some(device) = devices.remove(&old)
+        {
+            eprintln!(
+                "Device {} ({}) moved from {old} to {address}",
+                device.name, device.id
+            );
+            devices.insert(address, device);
+        }
+    }
+
+    if let Some(device) = devices.get(&address)
+        && !device.id.eq_ignore_ascii_case(id)
+        && warned.insert(address)
+    {
+        eprintln!(
+            "Device {} at {address} advertises ID {id}, but is configured as {}",
+            device.name, device.id
+        );
+    }
+}
+
+/// Watches the directory holding the config file rather than the file itself,
+/// so that changes are still seen after the file is replaced by a rename
+fn watch_config(path: &Path) -> notify::Result<(RecommendedWatcher, mpsc::UnboundedReceiver<()>)> {
+    let (tx, rx) = mpsc::unbounded_channel();
+    let file_name = path.file_name().map(ToOwned::to_owned);
+
+    let mut watcher = notify::recommended_watcher(move |res: notify::Result<notify::Event>| {
+        if let Ok(event) = res
+            && (event.kind.is_create() || event.kind.is_modify())
+            && event
+                .paths
+                .iter()
+                .any(|path| path.file_name() == file_name.as_deref())
+        {
+            let _ = tx.send(());
+        }
+    })?;
+
+    let dir = path
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    watcher.watch(dir, RecursiveMode::NonRecursive)?;
+
+    Ok((watcher, rx))
+}
+
+/// Tells systemd about the service's state. This does nothing when not run by
+/// systemd, and failures are only logged since they shouldn't stop the service.
+fn notify(state: &[NotifyState]) {
+    if let Err(e) = sd_notify::notify(state) {
+        eprintln!("Failed to notify systemd: {e}");
+    }
+}
+
+/// Prints readings from the configured devices, reloading the config file on
+/// SIGHUP or whenever it changes, until SIGTERM or SIGINT
+pub async fn run(
+    config_file: PathBuf,
+    state_file: Option<PathBuf>,
+    config: config::Config,
+) -> Result<()> {
+    let loaded = split(config)?;
+
+    let state = match &state_file {
+        Some(path) => state::load(path).await.unwrap_or_else(|e| {
+            eprintln!("{e:?}, starting without it");
+            state::State::default()
+        }),
+        None => state::State::default(),
+    };
+
+    let (_watcher, mut changes) = match watch_config(&config_file) {
+        Ok((watcher, changes)) => (Some(watcher), changes),
+        Err(e) => {
+            eprintln!(
+                "Not watching {} for changes, send SIGHUP to reload: {e}",
+                config_file.display()
+            );
+            (None, mpsc::unbounded_channel().1)
+        }
+    };
+    let mut hangup = signal(SignalKind::hangup())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+
+    // systemd expects a ping within the watchdog timeout, so ping at twice
+    // that rate
+    let watchdog = sd_notify::watchdog_enabled();
+    let mut tick = tokio::time::interval(watchdog.map_or(STATUS_INTERVAL, |timeout| {
+        (timeout / 2).min(STATUS_INTERVAL)
+    }));
+
+    let (done_tx, mut done_rx) = mpsc::unbounded_channel();
+    let mut daemon = Daemon {
+        config_file,
+        devices: loaded.devices,
+        warned: HashSet::new(),
+        last_reading: HashMap::new(),
+        stale: stale::Tracker::default(),
+        polls: HashMap::new(),
+        polling: HashSet::new(),
+        held: HashMap::new(),
+        done: done_tx,
+        filters: filter::Filters::default(),
+        alerts: alert::Engine::new(loaded.alerts),
+        ventilation: loaded.ventilation.map(ventilation::Estimator::new),
+        state,
+        state_file,
+        saved: Instant::now(),
+    };
+
+    let central = ble::start_scan().await?;
+    let mut events = central.events().await?;
+    let mut poll = tokio::time::interval(POLL_CHECK);
+
+    notify(&[NotifyState::Ready, NotifyState::Status(&daemon.status())]);
+
+    loop {
+        tokio::select! {
+            event = events.next() => {
+                let Some(event) = event else {
+                    break;
+                };
+                daemon.handle(&central, event).await;
+            }
+            _ = poll.tick() => {
+                daemon.poll(&central);
+            }
+            Some(done) = done_rx.recv() => {
+                daemon.finish(&central, done);
+            }
+            _ = hangup.recv() => {
+                eprintln!("Received SIGHUP, reloading {}", daemon.config_file.display());
+                daemon.reload().await;
+            }
+            Some(()) = changes.recv() => {
+                tokio::time::sleep(DEBOUNCE).await;
+                while changes.try_recv().is_ok() {}
+                daemon.reload().await;
+            }
+            _ = tick.tick() => {
+                daemon.check_stale();
+                if daemon.saved.elapsed() >= SAVE_INTERVAL {
+                    daemon.save().await;
+                }
+                let status = daemon.status();
+                let mut state = vec![NotifyState::Status(&status)];
+                if watchdog.is_some() {
+                    state.push(NotifyState::Watchdog);
+                }
+                notify(&state);
+            }
+            _ = terminate.recv() => {
+                eprintln!("Received SIGTERM, stopping");
+                break;
+            }
+            _ = interrupt.recv() => {
+                eprintln!("Received SIGINT, stopping");
+                break;
+            }
+        }
+    }
+
+    // Failing to stop cleanly, like when the adapter has gone away, mustn't
+    // stop the state from being saved
+    notify(&[NotifyState::Stopping]);
+    if let Err(e) = central.stop_scan().await {
+        eprintln!("Error stopping scan: {e}");
+    }
+    if let Err(e) = std::io::stdout().flush() {
+        eprintln!("Error flushing output: {e}");
+    }
+    daemon.save().await;
+
+    Ok(())
+}
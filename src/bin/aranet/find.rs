@@ -0,0 +1,270 @@
+use crate::ble;
+use anyhow::Result;
+use aranet::{
+    battery, config,
+    reading::{self, Device, ManufacturerData, Reading, Version},
+};
+use btleplug::api::{BDAddr, Central};
+use futures::stream::StreamExt;
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::{Duration, SystemTime},
+};
+
+/// Everything learned about a device over the course of a scan
+#[derive(Debug, Clone)]
+pub struct Discovered {
+    pub address: BDAddr,
+    pub local_name: Option<String>,
+    pub device: Option<Device>,
+    pub version: Option<Version>,
+    pub integrations: bool,
+    pub battery: Option<u8>,
+    pub rssi_min: Option<i16>,
+    pub rssi_max: Option<i16>,
+    rssi_sum: i64,
+    rssi_count: i64,
+}
+
+impl Discovered {
+    fn new(address: BDAddr) -> Self {
+        Self {
+            address,
+            local_name: None,
+            device: None,
+            version: None,
+            integrations: false,
+            battery: None,
+            rssi_min: None,
+            rssi_max: None,
+            rssi_sum: 0,
+            rssi_count: 0,
+        }
+    }
+
+    fn update(&mut self, advertisement: &ble::Advertisement, payload: &[u8]) {
+        if advertisement.local_name.is_some() {
+            self.local_name = advertisement.local_name.clone();
+        }
+
+        if let Ok(data) = ManufacturerData::try_from(payload) {
+            self.device = Some(data.device);
+            self.version = Some(data.version);
+            self.integrations |= data.integrations;
+        }
+
+        // A payload that parses into a reading is the surest sign that
+        // integrations are on
+        if let Ok(reading) = Reading::try_from(payload) {
+            self.device = Some(reading.device);
+            self.integrations = true;
+            self.battery = Some(reading.battery);
+        }
+
+        if self.device.is_none() {
+            self.device = self.local_name.as_deref().and_then(Device::from_local_name);
+        }
+
+        if let Some(rssi) = advertisement.rssi {
+            self.rssi_min = Some(self.rssi_min.map_or(rssi, |min| min.min(rssi)));
+            self.rssi_max = Some(self.rssi_max.map_or(rssi, |max| max.max(rssi)));
+            self.rssi_sum += rssi as i64;
+            self.rssi_count += 1;
+        }
+    }
+
+    pub fn rssi_avg(&self) -> Option<i16> {
+        (self.rssi_count > 0).then(|| (self.rssi_sum / self.rssi_count) as i16)
+    }
+
+    /// The device ID Aranet devices put at the end of their advertised name,
+    /// falling back to the address if the name doesn't have one
+    pub fn suggested_id(&self) -> String {
+        self.local_name
+            .as_deref()
+            .and_then(reading::id_from_local_name)
+            .map(String::from)
+            .unwrap_or_else(|| self.address.to_string_no_delim())
+    }
+
+    pub fn suggested_name(&self) -> String {
+        self.local_name
+            .clone()
+            .unwrap_or_else(|| format!("Aranet {}", self.address))
+    }
+}
+
+/// Listens for Aranet advertisements for the given duration, returning each
+/// device seen, sorted by address
+pub async fn discover(duration: Duration) -> Result<Vec<Discovered>> {
+    let central = ble::start_scan().await?;
+    let mut events = central.events().await?;
+    let mut discovered: HashMap<BDAddr, Discovered> = HashMap::new();
+
+    let deadline = tokio::time::sleep(duration);
+    tokio::pin!(deadline);
+
+    loop {
+        tokio::select! {
+            _ = &mut deadline => break,
+            event = events.next() => {
+                let Some(event) = event else {
+                    break;
+                };
+
+                let advertisement = match ble::advertisement(&central, event).await {
+                    Ok(Some(advertisement)) => advertisement,
+                    Ok(None) => continue,
+                    Err(e) => {
+                        eprintln!("{e:?}");
+                        continue;
+                    }
+                };
+
+                let Some(payload) = advertisement.payload() else {
+                    continue;
+                };
+
+                discovered
+                    .entry(advertisement.address)
+                    .or_insert_with(|| Discovered::new(advertisement.address))
+                    .update(&advertisement, payload);
+            }
+        }
+    }
+
+    central.stop_scan().await?;
+
+    let mut discovered = discovered.into_values().collect::<Vec<_>>();
+    discovered.sort_by_key(|d| d.address);
+    Ok(discovered)
+}
+
+/// Formats a string as a TOML basic string, with quotes
+pub fn toml_string(value: &str) -> String {
+    toml::Value::String(value.to_string()).to_string()
+}
+
+/// The configured device with the same ID as a discovered device at an
+/// address that isn't configured
+fn moved_from<'a>(
+    devices: &'a HashMap<BDAddr, config::Device>,
+    discovered: &Discovered,
+) -> Option<&'a config::Device> {
+    if devices.contains_key(&discovered.address) {
+        return None;
+    }
+
+    let id = reading::id_from_local_name(discovered.local_name.as_deref()?)?;
+    devices
+        .values()
+        .find(|device| device.id.eq_ignore_ascii_case(id))
+}
+
+pub async fn find(
+    devices: &HashMap<BDAddr, config::Device>,
+    batteries: &BTreeMap<String, battery::History>,
+    duration: Duration,
+    snippet: bool,
+) -> Result<()> {
+    eprintln!("Scanning for {}s...", duration.as_secs());
+    let discovered = discover(duration).await?;
+
+    if discovered.is_empty() {
+        println!("No Aranet devices found");
+        return Ok(());
+    }
+
+    let rows = discovered
+        .iter()
+        .map(|d| {
+            let rssi = match (d.rssi_min, d.rssi_avg(), d.rssi_max) {
+                (Some(min), Some(avg), Some(max)) => format!("{min}/{avg}/{max}"),
+                _ => String::from("-"),
+            };
+
+            let id = match devices.get(&d.address) {
+                Some(device) => device.id.clone(),
+                None => d.suggested_id(),
+            };
+            let battery = match (d.battery, d.device) {
+                (Some(battery), Some(device)) => {
+                    let forecast = match batteries.get(&id) {
+                        Some(history) => history.forecast(device, battery, SystemTime::now()),
+                        None => battery::modelled(device, battery),
+                    };
+                    format!("{battery}% ({forecast})")
+                }
+                _ => String::from("-"),
+            };
+
+            [
+                d.local_name.clone().unwrap_or_else(|| String::from("-")),
+                d.address.to_string(),
+                d.device
+                    .map_or_else(|| String::from("-"), |d| d.to_string()),
+                d.version
+                    .map_or_else(|| String::from("-"), |v| v.to_string()),
+                battery,
+                rssi,
+                String::from(if d.integrations { "yes" } else { "no" }),
+                match (devices.get(&d.address), moved_from(devices, d)) {
+                    (Some(device), _) => format!("yes ({})", device.name),
+                    (None, Some(device)) => {
+                        format!("moved from {} ({})", device.address, device.name)
+                    }
+                    (None, None) => String::from("no"),
+                },
+            ]
+        })
+        .collect::<Vec<_>>();
+
+    let header = [
+        "Name",
+        "Address",
+        "Type",
+        "Firmware",
+        "Battery",
+        "RSSI min/avg/max",
+        "Integrations",
+        "Configured",
+    ]
+    .map(String::from);
+
+    let mut widths = header.clone().map(|h| h.chars().count());
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    for row in std::iter::once(&header).chain(&rows) {
+        let line = row
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{cell:width$}"))
+            .collect::<Vec<_>>()
+            .join("  ");
+        println!("{}", line.trim_end());
+    }
+
+    let new = discovered
+        .iter()
+        .filter(|d| !devices.contains_key(&d.address) && moved_from(devices, d).is_none())
+        .collect::<Vec<_>>();
+
+    if snippet && !new.is_empty() {
+        println!();
+        println!("[devices]");
+        for d in new {
+            println!(
+                "{} = {{ address = {}, name = {} }}",
+                d.suggested_id(),
+                toml_string(&d.address.to_string()),
+                toml_string(&d.suggested_name()),
+            );
+        }
+    }
+
+    Ok(())
+}
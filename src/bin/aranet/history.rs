@@ -0,0 +1,186 @@
+use crate::{ble, select};
+use anyhow::{Result, bail};
+use aranet::{
+    averages::unix_secs,
+    config,
+    metric::Metric,
+    output::{Field, Point},
+    reading::Reading,
+};
+use btleplug::api::{BDAddr, Central};
+use clap::ValueEnum;
+use std::{collections::HashMap, time::Duration};
+
+/// How long to scan for the devices before giving up on them
+pub const SCAN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long downloading a device's history can take before it's given up on
+const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// The metrics in a device's history
+const METRICS: [Metric; 4] = [
+    Metric::Co2,
+    Metric::Temperature,
+    Metric::Humidity,
+    Metric::Pressure,
+];
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum Format {
+    /// The same line protocol as the service's own output
+    Influx,
+    Csv,
+    /// One JSON object per line
+    Json,
+}
+
+/// Writes a value as it's written in the line protocol, without the type
+fn plain(field: &Field) -> String {
+    match field {
+        Field::Integer(v) => v.to_string(),
+        Field::Float(v) => v.to_string(),
+        Field::Fixed(v, precision) => format!("{v:.precision$}"),
+        Field::Boolean(v) => v.to_string(),
+        Field::String(v) => v.clone(),
+    }
+}
+
+/// Writes a value as a JSON value, rounding fixed ones like the line
+/// protocol does
+fn json(field: Field) -> serde_json::Value {
+    match field {
+        Field::Integer(v) => v.into(),
+        Field::Float(v) => v.into(),
+        Field::Fixed(v, precision) => {
+            let scale = 10f64.powi(precision as i32);
+            ((v * scale).round() / scale).into()
+        }
+        Field::Boolean(v) => v.into(),
+        Field::String(v) => v.into(),
+    }
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// Prints readings from a device's history in the chosen format. Values are
+/// calibrated like the service's. The battery isn't kept in the history, so
+/// it's left out.
+pub fn print(device: &config::Device, readings: &[Reading], format: Format) {
+    for reading in readings {
+        let mut values = device.values(reading);
+        values.0.remove(&Metric::Battery);
+
+        match format {
+            Format::Influx => {
+                let mut point =
+                    Point::for_device("aranet", device, Some(reading.device), reading.time);
+                point.reading_fields(reading, &values);
+                println!("{point}");
+            }
+            Format::Csv => {
+                let mut row = vec![
+                    unix_secs(reading.time).to_string(),
+                    csv_field(&device.name),
+                    csv_field(&device.id),
+                ];
+                row.extend(METRICS.map(|metric| {
+                    values
+                        .get(metric)
+                        .map(|value| plain(&metric.field(value, reading)))
+                        .unwrap_or_default()
+                }));
+                println!("{}", row.join(","));
+            }
+            Format::Json => {
+                let mut object = serde_json::Map::new();
+                object.insert("time".into(), unix_secs(reading.time).into());
+                object.insert("name".into(), device.name.clone().into());
+                object.insert("device_id".into(), device.id.clone().into());
+                for metric in METRICS {
+                    if let Some(value) = values.get(metric) {
+                        object.insert(metric.name().into(), json(metric.field(value, reading)));
+                    }
+                }
+                println!("{}", serde_json::Value::Object(object));
+            }
+        }
+    }
+}
+
+pub async fn history(
+    devices: &HashMap<BDAddr, config::Device>,
+    selected: &[String],
+    since: Option<Duration>,
+    format: Format,
+) -> Result<()> {
+    let chosen = select(devices, selected)?;
+    let addresses = chosen
+        .iter()
+        .map(|(address, _)| *address)
+        .collect::<Vec<_>>();
+
+    let central = ble::start_scan().await?;
+    let seen = ble::wait_for(&central, &addresses, SCAN_TIMEOUT).await?;
+    central.stop_scan().await?;
+
+    if let Format::Csv = format {
+        let header = ["time", "name", "device_id"]
+            .into_iter()
+            .chain(METRICS.map(|metric| metric.name()))
+            .collect::<Vec<_>>();
+        println!("{}", header.join(","));
+    }
+
+    let mut failed = 0;
+    for (address, device) in chosen {
+        if !seen.contains(&address) {
+            eprintln!(
+                "Device {} ({address}) was not seen within {}s",
+                device.name,
+                SCAN_TIMEOUT.as_secs()
+            );
+            failed += 1;
+            continue;
+        }
+
+        eprintln!("Downloading history from {}...", device.name);
+        match tokio::time::timeout(
+            DOWNLOAD_TIMEOUT,
+            ble::read_history(&central, address, since),
+        )
+        .await
+        {
+            Ok(Ok(readings)) => {
+                eprintln!(
+                    "Downloaded {} readings from {}",
+                    readings.len(),
+                    device.name
+                );
+                print(device, &readings, format);
+            }
+            Ok(Err(e)) => {
+                eprintln!("Failed to download history from {}: {e:?}", device.name);
+                failed += 1;
+            }
+            Err(_) => {
+                eprintln!(
+                    "Timed out downloading history from {} after {}s",
+                    device.name,
+                    DOWNLOAD_TIMEOUT.as_secs()
+                );
+                failed += 1;
+            }
+        }
+    }
+
+    if failed > 0 {
+        bail!("Failed to download history from {failed} devices");
+    }
+    Ok(())
+}
@@ -0,0 +1,197 @@
+use crate::{ble, history::SCAN_TIMEOUT, select};
+use anyhow::{Result, bail};
+use aranet::{
+    config, gatt,
+    output::device_tag,
+    reading::{Device, ManufacturerData},
+};
+use btleplug::api::BDAddr;
+use btleplug::platform::Adapter;
+use clap::ValueEnum;
+use serde_json::json;
+use std::{collections::HashMap, time::Duration};
+
+/// How long reading a device can take before it's given up on
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum Format {
+    Table,
+    /// One JSON object per line
+    Json,
+}
+
+/// Everything that can be learned about a device. Any characteristic that
+/// can't be read is left out rather than failing the whole query.
+struct Info {
+    device: Device,
+    /// The header of the device's advertisement, which is the only place
+    /// with the calibration state and Bluetooth range
+    advertised: Option<ManufacturerData>,
+    model: Option<String>,
+    serial_number: Option<String>,
+    firmware: Option<String>,
+    hardware: Option<String>,
+    interval: Option<u16>,
+    since_update: Option<u16>,
+    total_readings: Option<u16>,
+}
+
+async fn read(central: &Adapter, address: BDAddr) -> Result<Info> {
+    let (peripheral, device) = ble::find_peripheral(central, address).await?;
+    let advertised = ble::advertised(&peripheral).await?;
+    let device = advertised.as_ref().map_or(device, |data| data.device);
+
+    ble::connected(&peripheral, async |peripheral| {
+        let string = async |uuid| {
+            ble::read(peripheral, uuid)
+                .await
+                .ok()
+                .map(|raw| gatt::parse_string(&raw))
+        };
+        let number = async |uuid| ble::read_u16(peripheral, uuid).await.ok();
+
+        Ok(Info {
+            device,
+            advertised,
+            model: string(gatt::MODEL_NUMBER).await,
+            serial_number: string(gatt::SERIAL_NUMBER).await,
+            firmware: string(gatt::FIRMWARE_REVISION).await,
+            hardware: string(gatt::HARDWARE_REVISION).await,
+            interval: number(gatt::INTERVAL).await,
+            since_update: number(gatt::SINCE_UPDATE).await,
+            total_readings: number(gatt::TOTAL_READINGS).await,
+        })
+    })
+    .await
+}
+
+fn print_table(device: &config::Device, address: BDAddr, info: &Info) {
+    let or_dash = |value: Option<String>| value.unwrap_or_else(|| String::from("-"));
+    let advertised = info.advertised.as_ref();
+
+    let rows = [
+        ("Address", address.to_string()),
+        ("Type", info.device.to_string()),
+        ("Model", or_dash(info.model.clone())),
+        ("Serial number", or_dash(info.serial_number.clone())),
+        ("Firmware", or_dash(info.firmware.clone())),
+        ("Hardware", or_dash(info.hardware.clone())),
+        ("Interval", or_dash(info.interval.map(|s| format!("{s}s")))),
+        (
+            "Last measurement",
+            or_dash(info.since_update.map(|s| format!("{s}s ago"))),
+        ),
+        (
+            "Stored readings",
+            or_dash(info.total_readings.map(|n| n.to_string())),
+        ),
+        (
+            "Calibration",
+            or_dash(advertised.map(|data| data.calibration.to_string())),
+        ),
+        (
+            "Bluetooth range",
+            or_dash(advertised.map(|data| {
+                String::from(if data.extended_range {
+                    "extended"
+                } else {
+                    "normal"
+                })
+            })),
+        ),
+        (
+            "Integrations",
+            or_dash(
+                advertised.map(|data| String::from(if data.integrations { "yes" } else { "no" })),
+            ),
+        ),
+    ];
+
+    let width = rows.iter().map(|(key, _)| key.len()).max().unwrap_or(0);
+    println!("{} ({})", device.name, device.id);
+    for (key, value) in rows {
+        println!("  {key:width$}  {value}");
+    }
+}
+
+fn print_json(device: &config::Device, address: BDAddr, info: &Info) {
+    let advertised = info.advertised.as_ref();
+    let object = json!({
+        "name": device.name,
+        "device_id": device.id,
+        "address": address.to_string(),
+        "device": device_tag(info.device),
+        "model": info.model,
+        "serial_number": info.serial_number,
+        "firmware": info.firmware,
+        "hardware": info.hardware,
+        "interval": info.interval,
+        "since_update": info.since_update,
+        "total_readings": info.total_readings,
+        "calibration": advertised.map(|data| data.calibration.to_string()),
+        "extended_range": advertised.map(|data| data.extended_range),
+        "integrations": advertised.map(|data| data.integrations),
+    });
+    println!("{object}");
+}
+
+pub async fn info(
+    devices: &HashMap<BDAddr, config::Device>,
+    selected: &[String],
+    format: Format,
+) -> Result<()> {
+    let chosen = select(devices, selected)?;
+    let addresses = chosen
+        .iter()
+        .map(|(address, _)| *address)
+        .collect::<Vec<_>>();
+
+    let failed = ble::scanning(async |central| {
+        let seen = ble::wait_for(central, &addresses, SCAN_TIMEOUT).await?;
+
+        let mut failed = 0;
+        for (i, (address, device)) in chosen.into_iter().enumerate() {
+            if !seen.contains(&address) {
+                eprintln!(
+                    "Device {} ({address}) was not seen within {}s",
+                    device.name,
+                    SCAN_TIMEOUT.as_secs()
+                );
+                failed += 1;
+                continue;
+            }
+
+            match tokio::time::timeout(READ_TIMEOUT, read(central, address)).await {
+                Ok(Ok(info)) => match format {
+                    Format::Table => {
+                        if i > 0 {
+                            println!();
+                        }
+                        print_table(device, address, &info);
+                    }
+                    Format::Json => print_json(device, address, &info),
+                },
+                Ok(Err(e)) => {
+                    eprintln!("Failed to read {}: {e:?}", device.name);
+                    failed += 1;
+                }
+                Err(_) => {
+                    eprintln!(
+                        "Timed out reading {} after {}s",
+                        device.name,
+                        READ_TIMEOUT.as_secs()
+                    );
+                    failed += 1;
+                }
+            }
+        }
+        Ok(failed)
+    })
+    .await?;
+
+    if failed > 0 {
+        bail!("Failed to read {failed} devices");
+    }
+    Ok(())
+}
@@ -0,0 +1,333 @@
+use anyhow::{Context, Result, anyhow, bail};
+use aranet::{config, duration, gatt::Setting};
+use btleplug::api::BDAddr;
+use clap::{Parser, Subcommand, ValueEnum};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+mod ble;
+mod config_cmd;
+mod daemon;
+mod find;
+mod history;
+mod info;
+mod reconcile;
+mod set;
+mod state;
+mod watch;
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum RunMode {
+    /// Print sensor readings from each configured device
+    Influx,
+    /// Print reachable Aranet devices
+    Find,
+}
+
+#[derive(Parser, Debug)]
+struct Args {
+    #[arg(long, env = "ARANET_CONFIG", default_value = "config.toml")]
+    config_file: PathBuf,
+
+    #[arg(long, short, default_value = "influx")]
+    mode: RunMode,
+
+    /// File to keep rolling averages and radiation doses in across restarts
+    #[arg(long, env = "ARANET_STATE")]
+    state_file: Option<PathBuf>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Show a live dashboard of configured and discovered devices
+    Watch,
+    /// Print reachable Aranet devices
+    Find(FindArgs),
+    /// Download the measurements kept on devices, over GATT
+    History(HistoryArgs),
+    /// Show what devices report about themselves, over GATT
+    Info(InfoArgs),
+    /// Change devices' settings, over GATT
+    Set(SetArgs),
+    /// Change devices' settings to match the config, over GATT
+    Reconcile(ReconcileArgs),
+    /// Manage the config file
+    #[command(subcommand)]
+    Config(ConfigCommand),
+}
+
+#[derive(Subcommand, Debug)]
+enum ConfigCommand {
+    /// Add a device to the config file, keeping existing formatting and
+    /// comments. Without --address, scans and asks which device to add.
+    Add(AddArgs),
+    /// Report every problem with the config file
+    Check(CheckArgs),
+}
+
+#[derive(clap::Args, Debug)]
+struct CheckArgs {
+    /// Also scan for devices and report any configured ones that aren't seen
+    #[arg(long)]
+    scan: bool,
+
+    /// Seconds to scan for devices with --scan
+    #[arg(long, default_value_t = 30)]
+    duration: u64,
+}
+
+#[derive(clap::Args, Debug)]
+struct AddArgs {
+    /// Bluetooth address of the device
+    #[arg(long)]
+    address: Option<String>,
+
+    /// Name to use for the device in output
+    #[arg(long)]
+    name: Option<String>,
+
+    /// Key for the device in the config, ideally its Aranet device ID
+    #[arg(long)]
+    id: Option<String>,
+
+    /// Seconds to scan for devices when no address is given
+    #[arg(long, default_value_t = 30)]
+    duration: u64,
+}
+
+#[derive(clap::Args, Debug)]
+struct FindArgs {
+    /// Seconds to listen for advertisements
+    #[arg(long, default_value_t = 30)]
+    duration: u64,
+
+    /// Print a `[devices]` snippet for devices not yet in the config
+    #[arg(long)]
+    snippet: bool,
+}
+
+#[derive(clap::Args, Debug)]
+struct HistoryArgs {
+    /// Name or ID of a configured device, which can be repeated. Every
+    /// configured device if not given.
+    #[arg(long)]
+    device: Vec<String>,
+
+    /// How far back to go, like `3d` or `12h`. Everything the device has if
+    /// not given.
+    #[arg(long, value_parser = duration::parse)]
+    since: Option<Duration>,
+
+    #[arg(long, value_enum, default_value = "influx")]
+    format: history::Format,
+}
+
+#[derive(clap::Args, Debug)]
+struct InfoArgs {
+    /// Name or ID of a configured device, which can be repeated. Every
+    /// configured device if not given.
+    #[arg(long)]
+    device: Vec<String>,
+
+    #[arg(long, value_enum, default_value = "table")]
+    format: info::Format,
+}
+
+#[derive(clap::Args, Debug)]
+struct SetArgs {
+    /// Name or ID of a configured device, which can be repeated
+    #[arg(long, required_unless_present = "all")]
+    device: Vec<String>,
+
+    /// Change every configured device
+    #[arg(long, conflicts_with = "device")]
+    all: bool,
+
+    /// Measurement interval in seconds: 60, 120, 300, or 600
+    #[arg(long)]
+    interval: Option<u16>,
+
+    /// Smart Home integrations, which put readings in advertisements
+    #[arg(long, value_enum)]
+    smart_home: Option<set::Switch>,
+
+    #[arg(long, value_enum)]
+    bluetooth_range: Option<set::Range>,
+}
+
+#[derive(clap::Args, Debug)]
+struct ReconcileArgs {
+    /// Name or ID of a configured device, which can be repeated. Every
+    /// configured device with settings if not given.
+    #[arg(long)]
+    device: Vec<String>,
+
+    /// Report what differs without changing anything
+    #[arg(long)]
+    dry_run: bool,
+}
+
+impl Default for FindArgs {
+    fn default() -> Self {
+        Self {
+            duration: 30,
+            snippet: false,
+        }
+    }
+}
+
+async fn load_config(path: &Path) -> Result<config::Config> {
+    let content = tokio::fs::read_to_string(path)
+        .await
+        .with_context(|| format!("Failed to read config file {}", path.display()))?;
+    Ok(config::Config::try_from(content.as_ref())?)
+}
+
+/// Writes to a temporary file then renames it into place, so anything
+/// watching the file never sees a partial write
+async fn write_atomic(path: &Path, content: &str) -> Result<()> {
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| anyhow!("{} has no file name", path.display()))?;
+    let tmp = path.with_file_name(format!(".{}.tmp", file_name.to_string_lossy()));
+
+    tokio::fs::write(&tmp, content)
+        .await
+        .with_context(|| format!("Failed to write {}", tmp.display()))?;
+    tokio::fs::rename(&tmp, path)
+        .await
+        .with_context(|| format!("Failed to replace {}", path.display()))?;
+
+    Ok(())
+}
+
+fn devices(config: config::Config) -> Result<HashMap<BDAddr, config::Device>> {
+    let mut configured = config.devices.into_values().collect::<Vec<_>>();
+    configured.sort_by(|a, b| a.id.cmp(&b.id));
+
+    let mut devices = HashMap::<BDAddr, config::Device>::new();
+    for device in configured {
+        let address = device.validate().map_err(|e| anyhow!(e))?;
+        if let Some(other) = devices.get(&address) {
+            bail!(
+                "Devices {} and {} have the same address {address}",
+                other.id,
+                device.id
+            );
+        }
+        devices.insert(address, device);
+    }
+    Ok(devices)
+}
+
+/// The configured devices with these names or IDs, sorted by name, or
+/// every configured device if none are given
+fn select<'a>(
+    devices: &'a HashMap<BDAddr, config::Device>,
+    selected: &[String],
+) -> Result<Vec<(BDAddr, &'a config::Device)>> {
+    let mut chosen = if selected.is_empty() {
+        devices
+            .iter()
+            .map(|(address, device)| (*address, device))
+            .collect()
+    } else {
+        selected
+            .iter()
+            .map(|selected| {
+                devices
+                    .iter()
+                    .find(|(_, device)| device.name == *selected || device.id == *selected)
+                    .map(|(address, device)| (*address, device))
+                    .ok_or_else(|| anyhow!("Unknown device {selected}"))
+            })
+            .collect::<Result<Vec<_>>>()?
+    };
+    if chosen.is_empty() {
+        bail!("No devices configured");
+    }
+    chosen.sort_by(|a, b| a.1.name.cmp(&b.1.name));
+    chosen.dedup_by_key(|(address, _)| *address);
+    Ok(chosen)
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse();
+
+    if let Some(Command::Config(command)) = args.command {
+        return match command {
+            ConfigCommand::Add(add) => {
+                let duration = Duration::from_secs(add.duration);
+                config_cmd::add(&args.config_file, add.address, add.name, add.id, duration).await
+            }
+            ConfigCommand::Check(check) => {
+                let scan = check.scan.then(|| Duration::from_secs(check.duration));
+                config_cmd::check(&args.config_file, scan).await
+            }
+        };
+    }
+
+    let config = load_config(&args.config_file)
+        .await
+        .with_context(|| format!("Failed to load config file {}", args.config_file.display()))?;
+
+    let command = match (args.command, args.mode) {
+        (Some(command), _) => Some(command),
+        (None, RunMode::Find) => Some(Command::Find(FindArgs::default())),
+        (None, RunMode::Influx) => None,
+    };
+
+    // Battery forecasts in watch and find use the daemon's history if there
+    // is one
+    let batteries = match (&command, &args.state_file) {
+        (Some(Command::Watch | Command::Find(_)), Some(path)) => state::load(path).await?.batteries,
+        _ => Default::default(),
+    };
+
+    match command {
+        Some(Command::Watch) => watch::watch(devices(config)?, batteries).await?,
+        Some(Command::Find(find)) => {
+            let duration = Duration::from_secs(find.duration);
+            find::find(&devices(config)?, &batteries, duration, find.snippet).await?
+        }
+        Some(Command::History(history)) => {
+            history::history(
+                &devices(config)?,
+                &history.device,
+                history.since,
+                history.format,
+            )
+            .await?
+        }
+        Some(Command::Info(info)) => {
+            info::info(&devices(config)?, &info.device, info.format).await?
+        }
+        Some(Command::Set(set)) => {
+            let settings = [
+                set.interval.map(Setting::Interval),
+                set.smart_home
+                    .map(|switch| Setting::Integrations(matches!(switch, set::Switch::On))),
+                set.bluetooth_range
+                    .map(|range| Setting::ExtendedRange(matches!(range, set::Range::Extended))),
+            ]
+            .into_iter()
+            .flatten()
+            .collect::<Vec<_>>();
+            set::set(&devices(config)?, &set.device, &settings).await?
+        }
+        Some(Command::Reconcile(reconcile)) => {
+            reconcile::reconcile(&devices(config)?, &reconcile.device, reconcile.dry_run).await?
+        }
+        Some(Command::Config(_)) => unreachable!(),
+        None => daemon::run(args.config_file, args.state_file, config).await?,
+    }
+
+    Ok(())
+}
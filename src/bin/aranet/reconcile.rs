@@ -0,0 +1,156 @@
+use crate::{ble, history::SCAN_TIMEOUT, select, set};
+use anyhow::{Result, anyhow, bail};
+use aranet::{
+    config,
+    gatt::{self, Setting},
+};
+use btleplug::api::BDAddr;
+use btleplug::platform::Adapter;
+use std::{collections::HashMap, time::Duration};
+
+/// How long reading a device's settings can take before it's given up on
+const COMPARE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Each setting the device should have, paired with what it has now
+async fn compare(
+    central: &Adapter,
+    address: BDAddr,
+    settings: &gatt::Settings,
+) -> Result<Vec<(Setting, Setting)>> {
+    let (peripheral, _) = ble::find_peripheral(central, address).await?;
+    // The advertisement is the only place with integrations and range
+    let Some(data) = ble::advertised(&peripheral).await? else {
+        bail!("The device's advertisement didn't include its settings");
+    };
+
+    let desired = settings.settings();
+    for setting in &desired {
+        setting.validate(data.device).map_err(|e| anyhow!(e))?;
+    }
+
+    let interval = ble::connected(&peripheral, async |peripheral| {
+        ble::read_u16(peripheral, gatt::INTERVAL).await
+    })
+    .await?;
+
+    Ok(desired
+        .into_iter()
+        .map(|setting| (setting.current(interval, &data), setting))
+        .collect())
+}
+
+/// Brings each device's settings in line with the config, printing what
+/// differed. With `dry_run`, only prints.
+pub async fn reconcile(
+    devices: &HashMap<BDAddr, config::Device>,
+    selected: &[String],
+    dry_run: bool,
+) -> Result<()> {
+    let chosen = select(devices, selected)?
+        .into_iter()
+        .filter(|(_, device)| !device.settings.is_empty())
+        .collect::<Vec<_>>();
+    if chosen.is_empty() {
+        bail!("No devices have settings configured");
+    }
+    let addresses = chosen
+        .iter()
+        .map(|(address, _)| *address)
+        .collect::<Vec<_>>();
+
+    let (failed, pending, made) = ble::scanning(async |central| {
+        let seen = ble::wait_for(central, &addresses, SCAN_TIMEOUT).await?;
+
+        let mut failed = 0;
+        let mut pending = 0;
+        let mut made = 0;
+        for (address, device) in chosen {
+            println!("{} ({address})", device.name);
+
+            if !seen.contains(&address) {
+                println!("  not seen within {}s", SCAN_TIMEOUT.as_secs());
+                failed += 1;
+                continue;
+            }
+
+            let compared = match tokio::time::timeout(
+                COMPARE_TIMEOUT,
+                compare(central, address, &device.settings),
+            )
+            .await
+            {
+                Ok(Ok(compared)) => compared,
+                Ok(Err(e)) => {
+                    println!("  failed to read settings: {e:#}");
+                    failed += 1;
+                    continue;
+                }
+                Err(_) => {
+                    println!(
+                        "  timed out reading settings after {}s",
+                        COMPARE_TIMEOUT.as_secs()
+                    );
+                    failed += 1;
+                    continue;
+                }
+            };
+
+            for (current, desired) in &compared {
+                let name = desired.name();
+                if current == desired {
+                    println!("  {name:16}  {} (ok)", current.value());
+                } else {
+                    println!("  {name:16}  {} -> {}", current.value(), desired.value());
+                }
+            }
+
+            let differing = compared
+                .into_iter()
+                .filter(|(current, desired)| current != desired)
+                .map(|(_, desired)| desired)
+                .collect::<Vec<_>>();
+            if differing.is_empty() {
+                continue;
+            }
+            pending += differing.len();
+            if dry_run {
+                continue;
+            }
+
+            match tokio::time::timeout(set::APPLY_TIMEOUT, set::apply(central, address, &differing))
+                .await
+            {
+                Ok(Ok(())) => {
+                    println!("  applied");
+                    made += differing.len();
+                }
+                Ok(Err(e)) => {
+                    println!("  failed to apply: {e:#}");
+                    failed += 1;
+                }
+                Err(_) => {
+                    println!(
+                        "  timed out applying after {}s",
+                        set::APPLY_TIMEOUT.as_secs()
+                    );
+                    failed += 1;
+                }
+            }
+        }
+        Ok((failed, pending, made))
+    })
+    .await?;
+
+    println!();
+    match (pending, dry_run) {
+        (0, _) if failed == 0 => println!("Every device matches the config"),
+        (0, _) => println!("No changes to make on the devices that could be read"),
+        (n, true) => println!("{n} changes to make, run without --dry-run to make them"),
+        (n, false) => println!("Made {made} of {n} changes"),
+    }
+
+    if failed > 0 {
+        bail!("Failed to reconcile {failed} devices");
+    }
+    Ok(())
+}
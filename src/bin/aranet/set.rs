@@ -0,0 +1,138 @@
+use crate::{ble, history::SCAN_TIMEOUT, select};
+use anyhow::{Result, anyhow, bail};
+use aranet::{
+    config,
+    gatt::{self, Setting},
+    reading::ManufacturerData,
+};
+use btleplug::api::BDAddr;
+use btleplug::platform::Adapter;
+use clap::ValueEnum;
+use std::{collections::HashMap, time::Duration};
+
+/// How long to wait for an advertisement showing a changed setting
+const ADVERTISED_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long changing a device's settings can take before it's given up on,
+/// which includes `ADVERTISED_TIMEOUT`
+pub const APPLY_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum Switch {
+    On,
+    Off,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum Range {
+    Normal,
+    Extended,
+}
+
+/// Writes the settings to a device and checks that they took
+pub async fn apply(central: &Adapter, address: BDAddr, settings: &[Setting]) -> Result<()> {
+    let (peripheral, device) = ble::find_peripheral(central, address).await?;
+    let device = ble::advertised(&peripheral)
+        .await?
+        .map_or(device, |data| data.device);
+    for setting in settings {
+        setting.validate(device).map_err(|e| anyhow!(e))?;
+    }
+
+    ble::connected(&peripheral, async |peripheral| {
+        for setting in settings {
+            ble::write(peripheral, gatt::COMMAND, &setting.command()).await?;
+
+            if let Setting::Interval(interval) = setting {
+                let actual = ble::read_u16(peripheral, gatt::INTERVAL).await?;
+                if actual != *interval {
+                    bail!("Set interval to {interval}s but it reads back as {actual}s");
+                }
+            }
+        }
+        Ok(())
+    })
+    .await?;
+
+    // The others can only be read back from advertisements, which resume
+    // once disconnected
+    let advertised = |data: &ManufacturerData| {
+        settings.iter().all(|setting| match *setting {
+            Setting::Interval(_) => true,
+            Setting::Integrations(on) => data.integrations == on,
+            Setting::ExtendedRange(extended) => data.extended_range == extended,
+        })
+    };
+    if settings
+        .iter()
+        .any(|setting| !matches!(setting, Setting::Interval(_)))
+        && !ble::wait_for_advertised(central, address, ADVERTISED_TIMEOUT, advertised).await?
+    {
+        bail!(
+            "The new settings weren't advertised within {}s",
+            ADVERTISED_TIMEOUT.as_secs()
+        );
+    }
+
+    Ok(())
+}
+
+pub async fn set(
+    devices: &HashMap<BDAddr, config::Device>,
+    selected: &[String],
+    settings: &[Setting],
+) -> Result<()> {
+    if settings.is_empty() {
+        bail!("Nothing to set");
+    }
+
+    let chosen = select(devices, selected)?;
+    let addresses = chosen
+        .iter()
+        .map(|(address, _)| *address)
+        .collect::<Vec<_>>();
+
+    let failed = ble::scanning(async |central| {
+        let seen = ble::wait_for(central, &addresses, SCAN_TIMEOUT).await?;
+
+        let mut failed = 0;
+        for (address, device) in chosen {
+            if !seen.contains(&address) {
+                eprintln!(
+                    "Device {} ({address}) was not seen within {}s",
+                    device.name,
+                    SCAN_TIMEOUT.as_secs()
+                );
+                failed += 1;
+                continue;
+            }
+
+            match tokio::time::timeout(APPLY_TIMEOUT, apply(central, address, settings)).await {
+                Ok(Ok(())) => {
+                    for setting in settings {
+                        eprintln!("Set {setting} on {}", device.name);
+                    }
+                }
+                Ok(Err(e)) => {
+                    eprintln!("Failed to change settings on {}: {e:?}", device.name);
+                    failed += 1;
+                }
+                Err(_) => {
+                    eprintln!(
+                        "Timed out changing settings on {} after {}s",
+                        device.name,
+                        APPLY_TIMEOUT.as_secs()
+                    );
+                    failed += 1;
+                }
+            }
+        }
+        Ok(failed)
+    })
+    .await?;
+
+    if failed > 0 {
+        bail!("Failed to change settings on {failed} devices");
+    }
+    Ok(())
+}
@@ -0,0 +1,46 @@
+use crate::write_atomic;
+use anyhow::{Context, Result};
+use aranet::{alert, averages, battery, dose};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, path::Path};
+
+/// Everything the daemon keeps across restarts
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct State {
+    #[serde(default)]
+    pub averages: averages::Tracker,
+    /// Lifetime radiation dose by device ID. These are kept even for devices
+    /// removed from the config, since they can't be recovered.
+    #[serde(default)]
+    pub doses: BTreeMap<String, dose::Accumulator>,
+    /// Battery levels by device ID, for forecasting when they'll run out
+    #[serde(default)]
+    pub batteries: BTreeMap<String, battery::History>,
+    /// When each device's last reading was output, in Unix seconds by
+    /// device ID, so that gaps from while the service was down can be
+    /// backfilled
+    #[serde(default)]
+    pub last_times: BTreeMap<String, u64>,
+    /// The windows of alert rules with a `mean`
+    #[serde(default)]
+    pub alert_means: alert::Means,
+}
+
+/// Reads the state file, treating a missing file as empty so that the first
+/// run starts fresh
+pub async fn load(path: &Path) -> Result<State> {
+    let content = match tokio::fs::read_to_string(path).await {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(State::default()),
+        Err(e) => {
+            return Err(e).with_context(|| format!("Failed to read state file {}", path.display()));
+        }
+    };
+
+    serde_json::from_str(&content)
+        .with_context(|| format!("Failed to parse state file {}", path.display()))
+}
+
+pub async fn save(path: &Path, state: &State) -> Result<()> {
+    write_atomic(path, &serde_json::to_string(state)?).await
+}
@@ -0,0 +1,450 @@
+use crate::ble;
+use anyhow::Result;
+use aranet::{
+    battery, config,
+    reading::{self, Device, Humidity, Reading},
+};
+use btleplug::api::{BDAddr, Central};
+use btleplug::platform::Adapter;
+use futures::stream::StreamExt;
+use ratatui::{
+    DefaultTerminal, Frame,
+    crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers},
+    layout::Constraint,
+    style::{Color, Style, Stylize},
+    text::Line,
+    widgets::{Block, Cell, Row, Table},
+};
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+/// How far back the sparkline column reaches
+const HISTORY: Duration = Duration::from_secs(60 * 60);
+
+/// Number of characters in the sparkline column
+const SPARKLINE_WIDTH: usize = 20;
+
+const SPARKLINE_BARS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+#[derive(Default)]
+struct Entry {
+    local_name: Option<String>,
+    rssi: Option<i16>,
+    reading: Option<Reading>,
+    history: VecDeque<(Instant, f64)>,
+}
+
+struct Dashboard {
+    devices: HashMap<BDAddr, config::Device>,
+    entries: HashMap<BDAddr, Entry>,
+    /// Battery history from the daemon's state file, by device ID
+    batteries: BTreeMap<String, battery::History>,
+}
+
+impl Dashboard {
+    fn new(
+        devices: HashMap<BDAddr, config::Device>,
+        batteries: BTreeMap<String, battery::History>,
+    ) -> Self {
+        let entries = devices
+            .keys()
+            .map(|address| (*address, Entry::default()))
+            .collect();
+
+        Self {
+            devices,
+            entries,
+            batteries,
+        }
+    }
+
+    fn update(&mut self, advertisement: ble::Advertisement) {
+        let Some(payload) = advertisement.payload() else {
+            return;
+        };
+
+        let entry = self.entries.entry(advertisement.address).or_default();
+        if advertisement.local_name.is_some() {
+            entry.local_name = advertisement.local_name.clone();
+        }
+        entry.rssi = advertisement.rssi;
+
+        // Devices without Smart Home integrations still show up, just
+        // without any readings
+        let Ok(reading) = Reading::try_from(payload) else {
+            return;
+        };
+
+        if let Some(last) = &entry.reading
+            && last.is_repeat_reading(&reading)
+        {
+            return;
+        }
+
+        if let Some(value) = primary_value(&reading) {
+            entry.history.push_back((reading.instant, value));
+        }
+        while let Some((instant, _)) = entry.history.front() {
+            if instant.elapsed() <= HISTORY {
+                break;
+            }
+            entry.history.pop_front();
+        }
+
+        entry.reading = Some(reading);
+    }
+
+    /// The configured device an entry is for, by its address, or else by the
+    /// ID it advertises in case it has moved to a new address
+    fn device(&self, address: &BDAddr, entry: &Entry) -> Option<&config::Device> {
+        self.devices.get(address).or_else(|| {
+            let id = reading::id_from_local_name(entry.local_name.as_deref()?)?;
+            self.devices
+                .values()
+                .find(|device| device.id.eq_ignore_ascii_case(id))
+        })
+    }
+
+    fn draw(&self, frame: &mut Frame) {
+        let mut addresses = self.entries.keys().copied().collect::<Vec<_>>();
+        addresses.sort_by_key(
+            |address| match self.device(address, &self.entries[address]) {
+                Some(device) => (0, device.name.clone()),
+                None => (1, address.to_string()),
+            },
+        );
+
+        let rows = addresses.iter().map(|address| {
+            let entry = &self.entries[address];
+            self.row(address, entry)
+        });
+
+        let header = Row::new([
+            "Name",
+            "Address",
+            "Type",
+            "CO₂",
+            "Radon",
+            "Radiation",
+            "Temp",
+            "Humidity",
+            "Pressure",
+            "Battery",
+            "RSSI",
+            "Age",
+            "Last hour",
+        ])
+        .bold();
+
+        let widths = [
+            Constraint::Min(12),
+            Constraint::Length(17),
+            Constraint::Length(15),
+            Constraint::Length(8),
+            Constraint::Length(10),
+            Constraint::Length(11),
+            Constraint::Length(7),
+            Constraint::Length(8),
+            Constraint::Length(10),
+            Constraint::Length(11),
+            Constraint::Length(5),
+            Constraint::Length(11),
+            Constraint::Length(SPARKLINE_WIDTH as u16),
+        ];
+
+        let configured = self.devices.len();
+        let discovered = self
+            .entries
+            .iter()
+            .filter(|(address, entry)| self.device(address, entry).is_none())
+            .count();
+        let title = format!(" Aranet: {configured} configured, {discovered} discovered ");
+
+        let table = Table::new(rows, widths).header(header).block(
+            Block::bordered()
+                .title(title)
+                .title_bottom(Line::from(" q to quit ").right_aligned()),
+        );
+
+        frame.render_widget(table, frame.area());
+    }
+
+    fn row<'a>(&self, address: &BDAddr, entry: &'a Entry) -> Row<'a> {
+        let name = match (self.device(address, entry), &entry.local_name) {
+            (Some(device), _) => Cell::from(device.name.clone()),
+            (None, Some(local_name)) => Cell::from(local_name.clone()).italic(),
+            (None, None) => Cell::from("(unnamed)").italic(),
+        };
+
+        let rssi = match entry.rssi {
+            Some(rssi) => Cell::from(rssi.to_string()).style(rssi_style(rssi)),
+            None => Cell::from(""),
+        };
+
+        let Some(reading) = &entry.reading else {
+            let mut cells = vec![name, Cell::from(address.to_string())];
+            cells.extend((0..8).map(|_| Cell::from("")));
+            cells.extend([rssi, Cell::from("no data").dark_gray()]);
+            return Row::new(cells);
+        };
+
+        let co2 = match reading.co2 {
+            Some(Ok(co2)) => Cell::from(format!("{co2} ppm")).style(co2_style(co2)),
+            Some(Err(e)) => Cell::from(e.to_string()).dark_gray(),
+            None => Cell::from(""),
+        };
+
+        let radon = match reading.radon {
+            Some(Ok(radon)) => Cell::from(format!("{radon} Bq/m³")).style(radon_style(radon)),
+            Some(Err(e)) => Cell::from(e.to_string()).dark_gray(),
+            None => Cell::from(""),
+        };
+
+        let radiation = match &reading.radiation {
+            Some(radiation) => {
+                let rate = radiation.raw_rate as f32 / 1000.0;
+                Cell::from(format!("{rate:.2} µSv/h")).style(radiation_style(rate))
+            }
+            None => Cell::from(""),
+        };
+
+        let temperature = match reading.celsius() {
+            Some(Ok(celsius)) => Cell::from(format!("{celsius:.1}°C")),
+            Some(Err(e)) => Cell::from(e.to_string()).dark_gray(),
+            None => Cell::from(""),
+        };
+
+        let humidity = match reading.raw_humidity {
+            Some(Ok(Humidity::V1(v))) => Cell::from(format!("{v}%")),
+            Some(Ok(Humidity::V2(v))) => Cell::from(format!("{:.1}%", v as f32 * 0.1)),
+            Some(Err(e)) => Cell::from(e.to_string()).dark_gray(),
+            None => Cell::from(""),
+        };
+
+        let pressure = match reading.pressure_hpa() {
+            Some(Ok(hpa)) => Cell::from(format!("{hpa:.1} hPa")),
+            Some(Err(e)) => Cell::from(e.to_string()).dark_gray(),
+            None => Cell::from(""),
+        };
+
+        let id = match (self.device(address, entry), &entry.local_name) {
+            (Some(device), _) => Some(device.id.as_str()),
+            (None, Some(local_name)) => reading::id_from_local_name(local_name),
+            (None, None) => None,
+        };
+        let forecast = match id.and_then(|id| self.batteries.get(id)) {
+            Some(history) => history.forecast(reading.device, reading.battery, reading.time),
+            None => battery::modelled(reading.device, reading.battery),
+        };
+        let battery = Cell::from(format!("{}% {forecast}", reading.battery))
+            .style(battery_style(reading.battery));
+
+        let age = reading.instant.elapsed().as_secs();
+        let age = Cell::from(format!("{age}s / {}s", reading.interval))
+            .style(age_style(age, reading.interval));
+
+        let sparkline = Cell::from(sparkline(&entry.history, Instant::now()));
+
+        Row::new([
+            name,
+            Cell::from(address.to_string()),
+            Cell::from(reading.device.to_string()),
+            co2,
+            radon,
+            radiation,
+            temperature,
+            humidity,
+            pressure,
+            battery,
+            rssi,
+            age,
+            sparkline,
+        ])
+    }
+}
+
+/// The value plotted in the sparkline column for each type of device
+fn primary_value(reading: &Reading) -> Option<f64> {
+    match reading.device {
+        Device::Aranet4 => reading.co2.and_then(Result::ok).map(f64::from),
+        Device::AranetRadon => reading.radon.and_then(Result::ok).map(f64::from),
+        Device::AranetRadiation => reading
+            .radiation
+            .as_ref()
+            .map(|radiation| radiation.raw_rate as f64 / 1000.0),
+        Device::Aranet2 => reading.celsius().and_then(Result::ok).map(f64::from),
+    }
+}
+
+/// Renders the last hour of values as a row of block characters, one
+/// character per time slice. Slices without any readings are left blank.
+fn sparkline(history: &VecDeque<(Instant, f64)>, now: Instant) -> String {
+    let slice = HISTORY / SPARKLINE_WIDTH as u32;
+    let mut buckets = [(0.0, 0); SPARKLINE_WIDTH];
+
+    for (instant, value) in history {
+        let ago = now.saturating_duration_since(*instant);
+        let Some(index) =
+            SPARKLINE_WIDTH.checked_sub(1 + (ago.as_secs() / slice.as_secs()) as usize)
+        else {
+            continue;
+        };
+        buckets[index].0 += value;
+        buckets[index].1 += 1;
+    }
+
+    let means = buckets
+        .iter()
+        .map(|(sum, count)| (*count > 0).then(|| sum / *count as f64))
+        .collect::<Vec<_>>();
+
+    let min = means
+        .iter()
+        .flatten()
+        .copied()
+        .fold(f64::INFINITY, f64::min);
+    let max = means
+        .iter()
+        .flatten()
+        .copied()
+        .fold(f64::NEG_INFINITY, f64::max);
+
+    means
+        .iter()
+        .map(|mean| match mean {
+            None => ' ',
+            Some(_) if max <= min => SPARKLINE_BARS[0],
+            Some(v) => {
+                let scaled = (v - min) / (max - min) * (SPARKLINE_BARS.len() - 1) as f64;
+                SPARKLINE_BARS[scaled.round() as usize]
+            }
+        })
+        .collect()
+}
+
+fn level(green: bool, yellow: bool) -> Style {
+    if green {
+        Style::new().fg(Color::Green)
+    } else if yellow {
+        Style::new().fg(Color::Yellow)
+    } else {
+        Style::new().fg(Color::Red)
+    }
+}
+
+/// Matches the thresholds of the Aranet4's own display
+fn co2_style(ppm: u16) -> Style {
+    level(ppm < 1000, ppm < 1400)
+}
+
+fn radon_style(bq: u16) -> Style {
+    level(bq < 100, bq < 300)
+}
+
+fn radiation_style(usv: f32) -> Style {
+    level(usv < 0.3, usv < 1.0)
+}
+
+fn battery_style(percent: u8) -> Style {
+    level(percent >= 30, percent >= 15)
+}
+
+fn rssi_style(rssi: i16) -> Style {
+    level(rssi >= -80, rssi >= -90)
+}
+
+fn age_style(age: u64, interval: u16) -> Style {
+    let interval = interval as u64;
+    level(age <= interval, age <= interval * 3)
+}
+
+pub async fn watch(
+    devices: HashMap<BDAddr, config::Device>,
+    batteries: BTreeMap<String, battery::History>,
+) -> Result<()> {
+    let central = ble::start_scan().await?;
+    let mut dashboard = Dashboard::new(devices, batteries);
+
+    let mut terminal = ratatui::init();
+    let res = run(&mut terminal, &central, &mut dashboard).await;
+    ratatui::restore();
+
+    res
+}
+
+async fn run(
+    terminal: &mut DefaultTerminal,
+    central: &Adapter,
+    dashboard: &mut Dashboard,
+) -> Result<()> {
+    let mut events = central.events().await?;
+    let mut tick = tokio::time::interval(Duration::from_millis(250));
+
+    loop {
+        tokio::select! {
+            event = events.next() => {
+                let Some(event) = event else {
+                    return Ok(());
+                };
+
+                // There's nowhere to report errors while the dashboard owns
+                // the terminal, and the next advertisement will be along soon
+                if let Ok(Some(advertisement)) = ble::advertisement(central, event).await {
+                    dashboard.update(advertisement);
+                }
+            }
+            _ = tick.tick() => {
+                while event::poll(Duration::ZERO)? {
+                    if let Event::Key(key) = event::read()?
+                        && key.kind == KeyEventKind::Press
+                    {
+                        match key.code {
+                            KeyCode::Char('q') | KeyCode::Esc => return Ok(()),
+                            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                                return Ok(());
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+
+                terminal.draw(|frame| dashboard.draw(frame))?;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sparkline() {
+        let start = Instant::now();
+        let slice = HISTORY / SPARKLINE_WIDTH as u32;
+        let at = |i: u32| start + slice * i;
+        let now = at(SPARKLINE_WIDTH as u32 - 1) + Duration::from_secs(1);
+
+        assert_eq!(
+            sparkline(&VecDeque::new(), now),
+            " ".repeat(SPARKLINE_WIDTH)
+        );
+
+        let flat = VecDeque::from([(at(17), 5.0), (at(18), 5.0), (at(19), 5.0)]);
+        assert_eq!(
+            sparkline(&flat, now),
+            format!("{}▁▁▁", " ".repeat(SPARKLINE_WIDTH - 3))
+        );
+
+        let rising = (0..SPARKLINE_WIDTH as u32)
+            .map(|i| (at(i), i as f64))
+            .collect::<VecDeque<_>>();
+        let bars = sparkline(&rising, now).chars().collect::<Vec<_>>();
+        assert_eq!(bars.len(), SPARKLINE_WIDTH);
+        assert_eq!(bars.first(), Some(&'▁'));
+        assert_eq!(bars.last(), Some(&'█'));
+        assert!(bars.windows(2).all(|pair| pair[0] <= pair[1]));
+    }
+}
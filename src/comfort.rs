@@ -0,0 +1,118 @@
+/// Magnus formula coefficients over water, from Sonntag (1990)
+const MAGNUS_B: f64 = 17.62;
+const MAGNUS_C: f64 = 243.12;
+
+/// Measures of comfort and condensation risk derived from temperature and
+/// relative humidity
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Comfort {
+    /// °C
+    pub dew_point: f64,
+    /// Grams of water vapour per m³ of air
+    pub absolute_humidity: f64,
+    /// Environment Canada's humidex, as a temperature in °C
+    pub humidex: f64,
+    /// The US National Weather Service's heat index, in °C
+    pub heat_index: f64,
+    /// Vapour pressure deficit, in kPa
+    pub vpd: f64,
+}
+
+/// Saturation vapour pressure in hPa at a temperature in °C
+fn saturation_pressure(celsius: f64) -> f64 {
+    6.112 * (MAGNUS_B * celsius / (MAGNUS_C + celsius)).exp()
+}
+
+impl Comfort {
+    /// From a temperature in °C and relative humidity in %, or `None` if the
+    /// humidity isn't above 0 and at most 100
+    pub fn new(celsius: f64, humidity: f64) -> Option<Self> {
+        if !(humidity > 0.0 && humidity <= 100.0 && celsius.is_finite()) {
+            return None;
+        }
+
+        let saturation = saturation_pressure(celsius);
+        let vapour = saturation * humidity / 100.0;
+
+        let gamma = (humidity / 100.0).ln() + MAGNUS_B * celsius / (MAGNUS_C + celsius);
+        let dew_point = MAGNUS_C * gamma / (MAGNUS_B - gamma);
+
+        let absolute_humidity = 216.7 * vapour / (celsius + 273.15);
+
+        let dew_vapour = 6.11 * (5417.7530 * (1.0 / 273.16 - 1.0 / (dew_point + 273.15))).exp();
+        let humidex = celsius + 0.5555 * (dew_vapour - 10.0);
+
+        Some(Comfort {
+            dew_point,
+            absolute_humidity,
+            humidex,
+            heat_index: heat_index(celsius, humidity),
+            vpd: (saturation - vapour) / 10.0,
+        })
+    }
+}
+
+/// The NWS heat index: Steadman's simple formula when it's mild, otherwise
+/// the Rothfusz regression with its adjustments. Both work in °F.
+fn heat_index(celsius: f64, humidity: f64) -> f64 {
+    let t = celsius * 9.0 / 5.0 + 32.0;
+    let rh = humidity;
+
+    let simple = 0.5 * (t + 61.0 + (t - 68.0) * 1.2 + rh * 0.094);
+    let fahrenheit = if (simple + t) / 2.0 < 80.0 {
+        simple
+    } else {
+        let mut hi = -42.379 + 2.04901523 * t + 10.14333127 * rh
+            - 0.22475541 * t * rh
+            - 0.00683783 * t * t
+            - 0.05481717 * rh * rh
+            + 0.00122874 * t * t * rh
+            + 0.00085282 * t * rh * rh
+            - 0.00000199 * t * t * rh * rh;
+
+        if rh < 13.0 && (80.0..=112.0).contains(&t) {
+            hi -= (13.0 - rh) / 4.0 * ((17.0 - (t - 95.0).abs()) / 17.0).sqrt();
+        } else if rh > 85.0 && (80.0..=87.0).contains(&t) {
+            hi += (rh - 85.0) / 10.0 * (87.0 - t) / 5.0;
+        }
+        hi
+    };
+
+    (fahrenheit - 32.0) * 5.0 / 9.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_near(actual: f64, expected: f64, tolerance: f64) {
+        assert!(
+            (actual - expected).abs() <= tolerance,
+            "{actual} is not within {tolerance} of {expected}"
+        );
+    }
+
+    #[test]
+    fn test_comfort() {
+        let comfort = Comfort::new(20.0, 50.0).unwrap();
+        assert_near(comfort.dew_point, 9.26, 0.01);
+        assert_near(comfort.absolute_humidity, 8.62, 0.01);
+        assert_near(comfort.humidex, 20.9, 0.05);
+        assert_near(comfort.heat_index, 19.4, 0.05);
+        assert_near(comfort.vpd, 1.17, 0.005);
+
+        let comfort = Comfort::new(32.0, 70.0).unwrap();
+        assert_near(comfort.dew_point, 25.84, 0.01);
+        assert_near(comfort.absolute_humidity, 23.59, 0.01);
+        assert_near(comfort.humidex, 45.3, 0.05);
+        assert_near(comfort.heat_index, 40.4, 0.05);
+
+        // Saturated air is at its dew point
+        let comfort = Comfort::new(15.0, 100.0).unwrap();
+        assert_near(comfort.dew_point, 15.0, 1e-9);
+        assert_near(comfort.vpd, 0.0, 1e-9);
+
+        assert_eq!(Comfort::new(20.0, 0.0), None);
+        assert_eq!(Comfort::new(20.0, 101.0), None);
+    }
+}
@@ -1,21 +1,1206 @@
-use serde::Deserialize;
-use std::collections::HashMap;
+use crate::{
+    alert, filter, gatt,
+    metric::{Metric, Values},
+    occupancy,
+    reading::{self, Reading},
+    stale::DEFAULT_STALE_AFTER,
+    ventilation,
+};
+use btleplug::api::BDAddr;
+use serde::{
+    Deserialize,
+    de::{DeserializeOwned, IntoDeserializer},
+};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    ops::Range,
+    str::FromStr,
+};
+use toml_edit::{ImDocument, Item, Value};
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct Config {
+    /// Tags added to the output of every device
+    #[serde(default)]
+    pub tags: BTreeMap<String, String>,
+    /// How many of its intervals any device can go without a reading before
+    /// it's reported as stale
+    pub stale_after: Option<f64>,
+    /// Whether to output dew point and the like for every device
+    pub comfort: Option<bool>,
+    /// Metrics to output rolling averages of for every device
+    pub averages: Option<Vec<Metric>>,
+    /// Whether to download missed readings from every device when it comes
+    /// back. They're output raw, without anything derived from them.
+    pub backfill: Option<bool>,
+    /// Settings every device should have, for `aranet reconcile`
+    #[serde(default)]
+    pub settings: gatt::Settings,
     pub devices: HashMap<String, Device>,
+    /// Alert rules, by name
+    #[serde(default)]
+    pub alerts: BTreeMap<String, alert::Rule>,
+    /// Air change estimates are only made if this is set
+    pub ventilation: Option<ventilation::Settings>,
 }
 
-#[derive(Debug, Deserialize)]
+/// Tags that the output already has, for every device or for alerts
+const RESERVED_TAGS: &[&str] = &["name", "device", "device_id", "alert", "metric"];
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct Device {
+    /// The device's key in the config, which is its Aranet device ID
+    #[serde(skip)]
+    pub id: String,
     pub address: String,
     pub name: String,
+    /// Tags added to this device's output. After loading, this includes the
+    /// config's global tags unless the device overrides them.
+    #[serde(default)]
+    pub tags: BTreeMap<String, String>,
+    /// Added to readings after scaling, e.g. `{ temperature = -0.8 }`
+    #[serde(default)]
+    pub offsets: BTreeMap<Metric, f64>,
+    /// Readings are multiplied by these, e.g. `{ co2 = 1.02 }`
+    #[serde(default)]
+    pub scale: BTreeMap<Metric, f64>,
+    /// Overrides the config's `stale_after` for this device
+    pub stale_after: Option<f64>,
+    /// Overrides the config's `comfort` for this device
+    pub comfort: Option<bool>,
+    /// Overrides the config's `averages` for this device
+    pub averages: Option<Vec<Metric>>,
+    /// The room's volume in m³, which enables occupancy estimates
+    pub room_volume: Option<f64>,
+    /// Litres of CO₂ each occupant breathes out per hour
+    pub co2_per_person: Option<f64>,
+    /// Smoothing for each metric, output alongside the unfiltered values
+    #[serde(default)]
+    pub filters: BTreeMap<Metric, filter::Filter>,
+    /// Polls the device over a GATT connection instead of waiting for
+    /// advertisements, for devices without Smart Home integrations
+    pub gatt: Option<bool>,
+    /// Overrides the config's `backfill` for this device
+    pub backfill: Option<bool>,
+    /// Settings this device should have, for `aranet reconcile`. After
+    /// loading, this includes the config's settings unless the device
+    /// overrides them.
+    #[serde(default)]
+    pub settings: gatt::Settings,
+}
+
+/// A difference between two loaded configs, for logging on reload
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+    Added {
+        id: String,
+        name: String,
+    },
+    Removed {
+        id: String,
+        name: String,
+    },
+    Renamed {
+        id: String,
+        from: String,
+        to: String,
+    },
+    Moved {
+        id: String,
+        name: String,
+        from: String,
+        to: String,
+    },
+    /// Tags or calibration changed
+    Updated {
+        id: String,
+        name: String,
+    },
+}
+
+impl Change {
+    pub fn id(&self) -> &str {
+        match self {
+            Change::Added { id, .. }
+            | Change::Removed { id, .. }
+            | Change::Renamed { id, .. }
+            | Change::Moved { id, .. }
+            | Change::Updated { id, .. } => id,
+        }
+    }
+}
+
+impl std::fmt::Display for Change {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Change::Added { id, name } => write!(f, "Added {name} ({id})"),
+            Change::Removed { id, name } => write!(f, "Removed {name} ({id})"),
+            Change::Renamed { id, from, to } => write!(f, "Renamed {from} ({id}) to {to}"),
+            Change::Moved { id, name, from, to } => {
+                write!(f, "Moved {name} ({id}) from {from} to {to}")
+            }
+            Change::Updated { id, name } => write!(f, "Updated {name} ({id})"),
+        }
+    }
+}
+
+/// Compares devices by ID, returning the changes sorted by ID
+pub fn changes<'a>(
+    old: impl IntoIterator<Item = &'a Device>,
+    new: impl IntoIterator<Item = &'a Device>,
+) -> Vec<Change> {
+    let old = old
+        .into_iter()
+        .map(|device| (device.id.as_str(), device))
+        .collect::<BTreeMap<_, _>>();
+    let new = new
+        .into_iter()
+        .map(|device| (device.id.as_str(), device))
+        .collect::<BTreeMap<_, _>>();
+
+    let mut changes = Vec::new();
+    for (&id, &device) in &new {
+        let Some(&previous) = old.get(id) else {
+            changes.push(Change::Added {
+                id: id.to_string(),
+                name: device.name.clone(),
+            });
+            continue;
+        };
+
+        if previous.name != device.name {
+            changes.push(Change::Renamed {
+                id: id.to_string(),
+                from: previous.name.clone(),
+                to: device.name.clone(),
+            });
+        }
+        if previous.bdaddr() != device.bdaddr() {
+            changes.push(Change::Moved {
+                id: id.to_string(),
+                name: device.name.clone(),
+                from: previous.address.clone(),
+                to: device.address.clone(),
+            });
+        }
+        // Anything else, like tags or calibration, differs if the previous
+        // device with this one's name and address still isn't the same
+        let moved = Device {
+            name: device.name.clone(),
+            address: device.address.clone(),
+            ..previous.clone()
+        };
+        if moved != *device {
+            changes.push(Change::Updated {
+                id: id.to_string(),
+                name: device.name.clone(),
+            });
+        }
+    }
+
+    for (&id, &device) in &old {
+        if !new.contains_key(id) {
+            changes.push(Change::Removed {
+                id: id.to_string(),
+                name: device.name.clone(),
+            });
+        }
+    }
+
+    changes.sort_by(|a, b| a.id().cmp(b.id()));
+    changes
+}
+
+/// A problem found by `Config::check`, with a 1-based position in the source
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl std::fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.message)
+    }
+}
+
+impl Diagnostic {
+    fn new(source: &str, span: Option<Range<usize>>, message: String) -> Self {
+        let offset = span.map_or(0, |span| span.start).min(source.len());
+        let before = &source[..offset];
+        let line = before.matches('\n').count() + 1;
+        let column = match before.rfind('\n') {
+            Some(newline) => before[newline + 1..].chars().count() + 1,
+            None => before.chars().count() + 1,
+        };
+
+        Diagnostic {
+            line,
+            column,
+            message,
+        }
+    }
 }
 
 impl TryFrom<&str> for Config {
     type Error = toml::de::Error;
 
     fn try_from(value: &str) -> Result<Self, Self::Error> {
-        toml::from_str(value)
+        let mut config: Config = toml::from_str(value)?;
+        for (id, device) in config.devices.iter_mut() {
+            device.id = id.clone();
+            for (key, value) in &config.tags {
+                device
+                    .tags
+                    .entry(key.clone())
+                    .or_insert_with(|| value.clone());
+            }
+            device.stale_after = device.stale_after.or(config.stale_after);
+            device.comfort = device.comfort.or(config.comfort);
+            device.backfill = device.backfill.or(config.backfill);
+            device.settings = device.settings.or(&config.settings);
+            if device.averages.is_none() {
+                device.averages = config.averages.clone();
+            }
+        }
+        Ok(config)
+    }
+}
+
+impl Config {
+    /// Checks config source for every problem at once, rather than stopping
+    /// at the first like `try_from` does. If `seen` is given, devices whose
+    /// address isn't in it are also reported.
+    pub fn check(source: &str, seen: Option<&HashSet<BDAddr>>) -> Vec<Diagnostic> {
+        let document = match ImDocument::parse(source) {
+            Ok(document) => document,
+            Err(e) => return vec![Diagnostic::new(source, e.span(), e.message().to_string())],
+        };
+
+        let mut diagnostics = Vec::new();
+        let root = document.as_table();
+
+        // Devices, alerts, and ventilation are each deserialized on their
+        // own below, so that a problem in one doesn't hide the others
+        let mut rest = root.clone();
+        for key in ["devices", "alerts"] {
+            if rest.get(key).is_some_and(Item::is_table_like) {
+                rest.insert(key, toml_edit::table());
+            }
+        }
+        if rest.get("ventilation").is_some_and(Item::is_table_like) {
+            rest.remove("ventilation");
+        }
+        let rest = Item::Table(rest);
+        if let Some(config) = deserialize::<Config>(source, &rest, None, "", &mut diagnostics) {
+            check_tags(
+                source,
+                get(&rest, "tags"),
+                &config.tags,
+                "",
+                &mut diagnostics,
+            );
+            if let Some(Err(e)) = config.stale_after.map(Device::validate_stale_after) {
+                diagnostics.push(Diagnostic::new(source, error_span(&rest, &e), e));
+            }
+            if let Err(e) = config.settings.validate() {
+                let span = error_span(get(&rest, "settings"), &e);
+                diagnostics.push(Diagnostic::new(source, span, format!("{e} in settings")));
+            }
+        }
+
+        let devices = root.get("devices").and_then(Item::as_table_like);
+        let ids = devices
+            .map(|devices| devices.iter().map(|(id, _)| id).collect())
+            .unwrap_or_default();
+
+        let mut addresses = HashMap::new();
+        let mut names = HashMap::new();
+        for (id, item) in devices.iter().flat_map(|devices| devices.iter()) {
+            let what = format!(" for device {id}");
+            let span = devices.and_then(|devices| devices.key(id)?.span());
+            let Some(device) = deserialize::<Device>(source, item, span, &what, &mut diagnostics)
+            else {
+                continue;
+            };
+            let mut push = |span: Option<Range<usize>>, message: String| {
+                diagnostics.push(Diagnostic::new(source, span, message));
+            };
+
+            let name_span = item.get("name").and_then(Item::span);
+            if let Err(e) = Device::validate_name(&device.name) {
+                push(name_span.clone(), e);
+            }
+            if let Some(other) = names.insert(device.name.clone(), id) {
+                push(
+                    name_span,
+                    format!(
+                        "Duplicate name {}, also used by device {other}",
+                        device.name
+                    ),
+                );
+            }
+
+            let address_span = item.get("address").and_then(Item::span);
+            match BDAddr::from_str(&device.address) {
+                Err(e) => push(
+                    address_span,
+                    format!("Invalid address {}: {e}", device.address),
+                ),
+                Ok(address) => {
+                    if let Some(other) = addresses.insert(address, id) {
+                        push(
+                            address_span.clone(),
+                            format!(
+                                "Duplicate address {}, also used by device {other}",
+                                device.address
+                            ),
+                        );
+                    }
+                    if let Some(seen) = seen
+                        && !seen.contains(&address)
+                    {
+                        push(
+                            address_span,
+                            format!(
+                                "Device {} ({}) was not seen during the scan",
+                                device.name, device.address
+                            ),
+                        );
+                    }
+                }
+            }
+
+            for (key, table) in [("offsets", &device.offsets), ("scale", &device.scale)] {
+                for metric in table.keys() {
+                    if let Err(e) = Device::validate_calibration(*metric) {
+                        let span = key_span(get(item, key), &metric.to_string());
+                        push(span, format!("{e} in {key}{what}"));
+                    }
+                }
+            }
+
+            let mut errors = Vec::new();
+            if let Some(stale_after) = device.stale_after {
+                errors.push(Device::validate_stale_after(stale_after));
+            }
+            for (key, value) in [
+                ("room_volume", device.room_volume),
+                ("co2_per_person", device.co2_per_person),
+            ] {
+                if let Some(value) = value {
+                    errors.push(Device::validate_positive(key, value));
+                }
+            }
+            for e in errors.into_iter().filter_map(Result::err) {
+                push(error_span(item, &e), format!("{e}{what}"));
+            }
+
+            for (metric, filter) in &device.filters {
+                if let Err(e) = filter.validate() {
+                    let item = get(get(item, "filters"), metric.name());
+                    push(
+                        error_span(item, &e),
+                        format!("{e} for {metric} in filters{what}"),
+                    );
+                }
+            }
+
+            if let Err(e) = device.settings.validate() {
+                let item = get(item, "settings");
+                push(error_span(item, &e), format!("{e} in settings{what}"));
+            }
+
+            check_tags(
+                source,
+                get(item, "tags"),
+                &device.tags,
+                &what,
+                &mut diagnostics,
+            );
+        }
+
+        let alerts = root.get("alerts").and_then(Item::as_table_like);
+        for (name, item) in alerts.iter().flat_map(|alerts| alerts.iter()) {
+            let what = format!(" in alert {name}");
+            let span = alerts.and_then(|alerts| alerts.key(name)?.span());
+            let Some(rule) =
+                deserialize::<alert::Rule>(source, item, span, &what, &mut diagnostics)
+            else {
+                continue;
+            };
+            if let Err(e) = rule.validate() {
+                diagnostics.push(Diagnostic::new(
+                    source,
+                    error_span(item, &e),
+                    format!("{e}{what}"),
+                ));
+            }
+            check_device_ids(source, get(item, "devices"), &ids, &what, &mut diagnostics);
+        }
+
+        if let Some((key, item)) = root.get_key_value("ventilation")
+            && item.is_table_like()
+        {
+            let what = " in ventilation";
+            if let Some(ventilation) = deserialize::<ventilation::Settings>(
+                source,
+                item,
+                key.span(),
+                what,
+                &mut diagnostics,
+            ) {
+                if let Err(e) = ventilation.validate() {
+                    diagnostics.push(Diagnostic::new(
+                        source,
+                        error_span(item, &e),
+                        format!("{e}{what}"),
+                    ));
+                }
+                check_device_ids(
+                    source,
+                    get(item, "outdoor_device"),
+                    &ids,
+                    what,
+                    &mut diagnostics,
+                );
+                check_device_ids(source, get(item, "devices"), &ids, what, &mut diagnostics);
+            }
+        }
+
+        // Deserializing the parts separately should find everything that
+        // loading would, but loading is what matters
+        if diagnostics.is_empty()
+            && let Err(e) = Config::try_from(source)
+        {
+            diagnostics.push(Diagnostic::new(source, e.span(), e.message().to_string()));
+        }
+
+        diagnostics.sort_by_key(|d| (d.line, d.column));
+        diagnostics
+    }
+
+    /// Checks everything about the alert rules that deserializing doesn't
+    pub fn validate_alerts(&self) -> Result<(), String> {
+        for (name, rule) in &self.alerts {
+            rule.validate()
+                .map_err(|e| format!("{e} in alert {name}"))?;
+            for id in &rule.devices {
+                if !self.devices.contains_key(id) {
+                    return Err(format!("Unknown device {id} in alert {name}"));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks the ventilation settings, and that they only refer to
+    /// configured devices
+    pub fn validate_ventilation(&self) -> Result<(), String> {
+        let Some(ventilation) = &self.ventilation else {
+            return Ok(());
+        };
+        ventilation
+            .validate()
+            .map_err(|e| format!("{e} in ventilation"))?;
+        for id in ventilation
+            .outdoor_device
+            .iter()
+            .chain(&ventilation.devices)
+        {
+            if !self.devices.contains_key(id) {
+                return Err(format!("Unknown device {id} in ventilation"));
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that a device could be added without colliding with any
+    /// existing device
+    pub fn check_new_device(&self, id: &str, address: BDAddr, name: &str) -> Result<(), String> {
+        Device::validate_name(name)?;
+
+        if self.devices.contains_key(id) {
+            return Err(format!("Device ID {id} is already in the config"));
+        }
+
+        for device in self.devices.values() {
+            if device.bdaddr().ok() == Some(address) {
+                return Err(format!(
+                    "Address {address} is already in the config as {}",
+                    device.name
+                ));
+            }
+            if device.name == name {
+                return Err(format!(
+                    "Name {name} is already in the config for {}",
+                    device.address
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Device {
+    pub fn bdaddr(&self) -> Result<BDAddr, String> {
+        BDAddr::from_str(&self.address).map_err(|e| format!("{e}: {}", self.address))
+    }
+
+    /// Checks everything about a loaded device that deserializing doesn't
+    pub fn validate(&self) -> Result<BDAddr, String> {
+        Device::validate_name(&self.name)?;
+        for (key, value) in &self.tags {
+            Device::validate_tag(key, value)?;
+        }
+        for metric in self.offsets.keys().chain(self.scale.keys()) {
+            Device::validate_calibration(*metric)?;
+        }
+        if let Some(stale_after) = self.stale_after {
+            Device::validate_stale_after(stale_after)?;
+        }
+        for (key, value) in [
+            ("room_volume", self.room_volume),
+            ("co2_per_person", self.co2_per_person),
+        ] {
+            if let Some(value) = value {
+                Device::validate_positive(key, value)?;
+            }
+        }
+        for (metric, filter) in &self.filters {
+            filter
+                .validate()
+                .map_err(|e| format!("{e} in filters for {metric}"))?;
+        }
+        self.settings
+            .validate()
+            .map_err(|e| format!("{e} in settings"))?;
+        self.bdaddr()
+    }
+
+    pub fn validate_tag(key: &str, value: &str) -> Result<(), String> {
+        if RESERVED_TAGS.contains(&key) {
+            return Err(format!("Tag {key} is reserved"));
+        }
+        if key.is_empty() || value.is_empty() {
+            return Err(format!("Tag {key} must have a non-empty key and value"));
+        }
+        // The same as device names, which are output as a tag too
+        for text in [key, value] {
+            if text.contains(['"', '\'']) {
+                return Err(format!("Tag {key} must not contain quotes"));
+            }
+            if text.contains('\\') {
+                return Err(format!("Tag {key} must not contain backslash"));
+            }
+            if text.contains(char::is_control) {
+                return Err(format!("Tag {key} must not contain newlines"));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn validate_calibration(metric: Metric) -> Result<(), String> {
+        if !metric.is_calibratable() {
+            return Err(format!("{metric} can't be calibrated"));
+        }
+        Ok(())
+    }
+
+    /// A device that's stale after less than one interval would be stale
+    /// between every reading
+    pub fn validate_stale_after(stale_after: f64) -> Result<(), String> {
+        if !(stale_after >= 1.0 && stale_after.is_finite()) {
+            return Err(format!("stale_after must be at least 1: {stale_after}"));
+        }
+        Ok(())
+    }
+
+    /// How many of its intervals the device can go without a reading before
+    /// it's reported as stale
+    pub fn stale_after(&self) -> f64 {
+        self.stale_after.unwrap_or(DEFAULT_STALE_AFTER)
+    }
+
+    pub fn validate_positive(key: &str, value: f64) -> Result<(), String> {
+        if !(value > 0.0 && value.is_finite()) {
+            return Err(format!("{key} must be a positive number: {value}"));
+        }
+        Ok(())
+    }
+
+    /// The room the device is in, if its volume is configured
+    pub fn room(&self) -> Option<occupancy::Room> {
+        Some(occupancy::Room {
+            volume: self.room_volume?,
+            co2_per_person: self
+                .co2_per_person
+                .unwrap_or(occupancy::DEFAULT_CO2_PER_PERSON),
+        })
+    }
+
+    /// The metrics to keep rolling averages of for a device of this type.
+    /// Radon is only meaningful as a long-term average, so it's always
+    /// included for radon devices.
+    pub fn averages(&self, kind: reading::Device) -> Vec<Metric> {
+        let mut metrics = self.averages.clone().unwrap_or_default();
+        if kind == reading::Device::AranetRadon && !metrics.contains(&Metric::Radon) {
+            metrics.push(Metric::Radon);
+        }
+        metrics
+    }
+
+    /// Whether to output dew point and the like for this device
+    pub fn comfort(&self) -> bool {
+        self.comfort.unwrap_or(false)
+    }
+
+    pub fn gatt(&self) -> bool {
+        self.gatt.unwrap_or(false)
+    }
+
+    pub fn backfill(&self) -> bool {
+        self.backfill.unwrap_or(false)
+    }
+
+    /// Corrects the reading's values with the device's offsets and scale
+    pub fn values(&self, reading: &Reading) -> Values {
+        let mut values = Values::from_reading(reading);
+        values.calibrate(&self.offsets, &self.scale);
+        values
+    }
+
+    /// Device names end up in InfluxDB tags, which can't represent every
+    /// character
+    pub fn validate_name(name: &str) -> Result<(), String> {
+        if name.contains('"') || name.contains('\'') {
+            return Err(format!("Device name must not contain quotes: {name}"));
+        }
+        if name.contains('\\') {
+            return Err(format!("Device name must not contain backslash: {name}"));
+        }
+        if name.contains(char::is_control) {
+            return Err(format!("Device name must not contain newlines: {name:?}"));
+        }
+        Ok(())
+    }
+}
+
+/// Deserializes `item`, reporting each unknown key and value of the wrong
+/// type that serde finds, with `what` appended. The key at fault is left out
+/// after each problem and `item` deserialized again, so that one problem
+/// doesn't hide the rest. Problems with no span of their own, like a missing
+/// key, point at `span`.
+fn deserialize<T: DeserializeOwned>(
+    source: &str,
+    item: &Item,
+    span: Option<Range<usize>>,
+    what: &str,
+    diagnostics: &mut Vec<Diagnostic>,
+) -> Option<T> {
+    let mut item = item.clone();
+    let mut removed = Vec::new();
+    loop {
+        let value = item.clone().into_value().ok()?;
+        let error = match T::deserialize(value.into_deserializer()) {
+            Ok(value) => return Some(value),
+            Err(error) => error,
+        };
+
+        // A required key that was left out has already been reported
+        let message = error.message();
+        if removed
+            .iter()
+            .any(|key| message == format!("missing field `{key}`"))
+        {
+            return None;
+        }
+        diagnostics.push(Diagnostic::new(
+            source,
+            error.span().or(span.clone()),
+            format!("{message}{what}"),
+        ));
+
+        // The key at fault is the last one that starts before the problem
+        let start = error.span()?.start;
+        let table = item.as_table_like_mut()?;
+        let key = table
+            .iter()
+            .filter_map(|(key, _)| Some((table.key(key)?.span()?.start, key.to_string())))
+            .filter(|(key_start, _)| *key_start <= start)
+            .max()?
+            .1;
+        table.remove(&key);
+        removed.push(key);
+    }
+}
+
+/// Where a validation error about a table points: at the value of the key
+/// its message starts with, like `interval` in "interval must be ...", or
+/// else at the table itself
+fn error_span(item: &Item, message: &str) -> Option<Range<usize>> {
+    let key = message.split(' ').next().unwrap_or_default();
+    item.get(key).and_then(Item::span).or_else(|| item.span())
+}
+
+/// The value of `key` in the table `item`, which unlike indexing doesn't
+/// panic if it's missing
+fn get<'a>(item: &'a Item, key: &str) -> &'a Item {
+    item.get(key).unwrap_or(&Item::None)
+}
+
+/// The span of `key` in the table `item`
+fn key_span(item: &Item, key: &str) -> Option<Range<usize>> {
+    item.as_table_like()?.key(key)?.span()
+}
+
+fn check_tags(
+    source: &str,
+    item: &Item,
+    tags: &BTreeMap<String, String>,
+    what: &str,
+    diagnostics: &mut Vec<Diagnostic>,
+) {
+    for (key, value) in tags {
+        if let Err(e) = Device::validate_tag(key, value) {
+            diagnostics.push(Diagnostic::new(
+                source,
+                key_span(item, key),
+                format!("{e} in tags{what}"),
+            ));
+        }
+    }
+}
+
+/// Reports device IDs in `item`, a string or an array of them, that aren't
+/// in the config
+fn check_device_ids(
+    source: &str,
+    item: &Item,
+    ids: &HashSet<&str>,
+    what: &str,
+    diagnostics: &mut Vec<Diagnostic>,
+) {
+    let values = match item {
+        Item::Value(Value::Array(array)) => array.iter().collect(),
+        Item::Value(value) => vec![value],
+        _ => Vec::new(),
+    };
+    for value in values {
+        if let Some(id) = value.as_str()
+            && !ids.contains(id)
+        {
+            diagnostics.push(Diagnostic::new(
+                source,
+                value.span(),
+                format!("Unknown device {id}{what}"),
+            ));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+[devices]
+11111 = { address = "01:23:45:67:89:AB", name = "Living room" }
+22222 = { address = "CD:EF:01:23:45:67", name = "Kitchen" }
+"#;
+
+    /// Uses every option, for `test_load` and `test_check`
+    const FULL: &str = r#"
+tags = { building = "HQ", floor = "1" }
+stale_after = 4
+comfort = true
+averages = ["co2"]
+backfill = true
+settings = { interval = 300, smart_home = true }
+
+[devices.11111]
+address = "01:23:45:67:89:AB"
+name = "Living room"
+tags = { floor = "2" }
+offsets = { temperature = -0.8, co2 = 10 }
+scale = { humidity = 1.05 }
+room_volume = 60
+filters = { co2 = { median = 3, max_rate = 200 } }
+gatt = true
+
+[devices.22222]
+address = "CD:EF:01:23:45:67"
+name = "Kitchen"
+stale_after = 2.5
+comfort = false
+averages = ["humidity"]
+room_volume = 200
+co2_per_person = 60
+backfill = false
+settings = { interval = 60, bluetooth_range = "extended" }
+
+[devices.33333]
+address = "89:AB:CD:EF:01:23"
+name = "Hall"
+
+[devices.99999]
+address = "89:AB:CD:EF:01:24"
+name = "Outside"
+
+[alerts]
+co2 = { metric = "co2", above = 1400, for = "10m", hysteresis = 100 }
+radon = { metric = "radon", above = 150, mean = "7d", devices = ["11111"] }
+
+[ventilation]
+outdoor_device = "99999"
+"#;
+
+    #[test]
+    fn test_check_new_device() {
+        let config = Config::try_from(CONFIG).unwrap();
+        let address = BDAddr::from_str("89:AB:CD:EF:01:23").unwrap();
+
+        assert!(config.check_new_device("33333", address, "Bedroom").is_ok());
+        assert!(
+            config
+                .check_new_device("11111", address, "Bedroom")
+                .is_err()
+        );
+        assert!(
+            config
+                .check_new_device("33333", address, "Kitchen")
+                .is_err()
+        );
+        assert!(
+            config
+                .check_new_device("33333", address, "Bed\"room")
+                .is_err()
+        );
+
+        let existing = BDAddr::from_str("cd:ef:01:23:45:67").unwrap();
+        assert!(
+            config
+                .check_new_device("33333", existing, "Bedroom")
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_changes() {
+        let old = Config::try_from(
+            r#"
+[devices]
+11111 = { address = "01:23:45:67:89:AB", name = "Living room" }
+22222 = { address = "01:23:45:67:89:AC", name = "Bedroom" }
+33333 = { address = "01:23:45:67:89:AD", name = "Office" }
+"#,
+        )
+        .unwrap();
+        let new = Config::try_from(
+            r#"
+[devices]
+11111 = { address = "01:23:45:67:89:ab", name = "Living room" }
+22222 = { address = "01:23:45:67:89:AE", name = "Main bedroom", offsets = { co2 = 5 } }
+44444 = { address = "01:23:45:67:89:AF", name = "Kitchen" }
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(changes(old.devices.values(), old.devices.values()), vec![]);
+
+        let changes = changes(old.devices.values(), new.devices.values())
+            .iter()
+            .map(|c| c.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            changes,
+            vec![
+                "Renamed Bedroom (22222) to Main bedroom",
+                "Moved Main bedroom (22222) from 01:23:45:67:89:AC to 01:23:45:67:89:AE",
+                "Updated Main bedroom (22222)",
+                "Removed Office (33333)",
+                "Added Kitchen (44444)",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_load() {
+        let config = Config::try_from(FULL).unwrap();
+        let device = |id: &str| &config.devices[id];
+        for device in config.devices.values() {
+            assert!(device.validate().is_ok());
+        }
+        assert_eq!(config.validate_alerts(), Ok(()));
+        assert_eq!(config.validate_ventilation(), Ok(()));
+
+        let tags = |id: &str| {
+            device(id)
+                .tags
+                .iter()
+                .map(|(k, v)| format!("{k}={v}"))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(tags("11111"), vec!["building=HQ", "floor=2"]);
+        assert_eq!(tags("22222"), vec!["building=HQ", "floor=1"]);
+
+        assert_eq!(device("11111").offsets[&Metric::Temperature], -0.8);
+        assert_eq!(device("11111").offsets[&Metric::Co2], 10.0);
+        assert_eq!(device("11111").scale[&Metric::Humidity], 1.05);
+
+        assert_eq!(device("11111").stale_after(), 4.0);
+        assert_eq!(device("22222").stale_after(), 2.5);
+
+        assert!(device("11111").comfort());
+        assert!(!device("22222").comfort());
+
+        assert_eq!(
+            device("11111").averages(reading::Device::Aranet4),
+            vec![Metric::Co2]
+        );
+        assert_eq!(
+            device("22222").averages(reading::Device::AranetRadon),
+            vec![Metric::Humidity, Metric::Radon]
+        );
+
+        assert_eq!(
+            device("11111").room(),
+            Some(occupancy::Room {
+                volume: 60.0,
+                co2_per_person: occupancy::DEFAULT_CO2_PER_PERSON,
+            })
+        );
+        assert_eq!(device("22222").room().unwrap().co2_per_person, 60.0);
+        assert_eq!(device("33333").room(), None);
+
+        assert_eq!(
+            device("11111").filters[&Metric::Co2],
+            filter::Filter {
+                max_rate: Some(200.0),
+                median: Some(3),
+                ema: None,
+            }
+        );
+
+        assert!(device("11111").gatt());
+        assert!(!device("22222").gatt());
+
+        assert!(device("11111").backfill());
+        assert!(!device("22222").backfill());
+
+        assert_eq!(
+            device("11111").settings,
+            gatt::Settings {
+                interval: Some(300),
+                smart_home: Some(true),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            device("22222").settings,
+            gatt::Settings {
+                interval: Some(60),
+                smart_home: Some(true),
+                bluetooth_range: Some(gatt::Range::Extended),
+            }
+        );
+
+        assert_eq!(config.alerts["co2"].above, Some(1400.0));
+        let ventilation = config.ventilation.as_ref().unwrap();
+        assert_eq!(ventilation.outdoor_co2, ventilation::DEFAULT_OUTDOOR_CO2);
+    }
+
+    #[test]
+    fn test_validate() {
+        let device = |fields: &str| {
+            let source = format!(
+                "[devices]\n11111 = {{ address = \"01:23:45:67:89:AB\", name = \"Living room\", {fields} }}"
+            );
+            Config::try_from(source.as_str()).unwrap().devices["11111"].validate()
+        };
+        assert_eq!(
+            device("stale_after = 0.5"),
+            Err(String::from("stale_after must be at least 1: 0.5"))
+        );
+        assert_eq!(
+            device("room_volume = 0"),
+            Err(String::from("room_volume must be a positive number: 0"))
+        );
+        assert_eq!(
+            device("filters = { co2 = { ema = 2 } }"),
+            Err(String::from(
+                "ema must be more than 0 and at most 1: 2 in filters for co2"
+            ))
+        );
+
+        let config = Config::try_from(
+            r#"
+[devices]
+11111 = { address = "01:23:45:67:89:AB", name = "Living room" }
+
+[alerts]
+radon = { metric = "radon", above = 150, devices = ["22222"] }
+
+[ventilation]
+outdoor_co2 = -1
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.validate_alerts(),
+            Err(String::from("Unknown device 22222 in alert radon"))
+        );
+        assert_eq!(
+            config.validate_ventilation(),
+            Err(String::from(
+                "outdoor_co2 must not be negative: -1 in ventilation"
+            ))
+        );
+    }
+
+    #[test]
+    fn test_check() {
+        let cases: &[(&str, &[&str])] = &[
+            (FULL, &[]),
+            (
+                r#"[devices]
+11111 = { address = "01:23:45:67:89:AB", name = "Living room" }
+22222 = { address = "01:23:45:67:89:ab", name = "Kitchen", room = "1" }
+33333 = { address = "not an address", name = "Kitchen" }
+44444 = { address = "89:AB:CD:EF:01:23", name = "Bed\\room" }
+55555 = { address = "89:AB:CD:EF:01:24", name = "Bed\nroom" }
+"#,
+                &[
+                    "3:21: Duplicate address 01:23:45:67:89:ab, also used by device 11111",
+                    "3:60: unknown field `room`, expected one of `address`, `name`, `tags`, `offsets`, `scale`, `stale_after`, `comfort`, `averages`, `room_volume`, `co2_per_person`, `filters`, `gatt`, `backfill`, `settings` for device 22222",
+                    "4:21: Invalid address not an address: Bluetooth address has to be 6 bytes long",
+                    "4:46: Duplicate name Kitchen, also used by device 22222",
+                    "5:49: Device name must not contain backslash: Bed\\room",
+                    "6:49: Device name must not contain newlines: \"Bed\\nroom\"",
+                ],
+            ),
+            (
+                r#"[device]
+"#,
+                &[
+                    "1:1: missing field `devices`",
+                    "1:2: unknown field `device`, expected one of `tags`, `stale_after`, `comfort`, `averages`, `backfill`, `settings`, `devices`, `alerts`, `ventilation`",
+                ],
+            ),
+            (
+                r#"tags = { name = "x" }
+stale_after = 0.5
+comfort = "yes"
+backfill = 1
+averages = ["co2", "wind"]
+settings = { interval = 90 }
+
+[devices.11111]
+address = "01:23:45:67:89:AB"
+name = "Living room"
+room = 1
+tags = { zone = "", a = 'C:\', b = "x\ny", c = "it's" }
+"#,
+                &[
+                    "1:10: Tag name is reserved in tags",
+                    "2:15: stale_after must be at least 1: 0.5",
+                    "3:11: invalid type: string \"yes\", expected a boolean",
+                    "4:12: invalid type: integer `1`, expected a boolean",
+                    "5:20: unknown variant `wind`, expected one of `co2`, `radon`, `radiation_rate`, `temperature`, `humidity`, `pressure`, `battery`",
+                    "6:25: interval must be 60, 120, 300, or 600 seconds: 90 in settings",
+                    "11:1: unknown field `room`, expected one of `address`, `name`, `tags`, `offsets`, `scale`, `stale_after`, `comfort`, `averages`, `room_volume`, `co2_per_person`, `filters`, `gatt`, `backfill`, `settings` for device 11111",
+                    "12:10: Tag zone must have a non-empty key and value in tags for device 11111",
+                    "12:21: Tag a must not contain backslash in tags for device 11111",
+                    "12:32: Tag b must not contain newlines in tags for device 11111",
+                    "12:44: Tag c must not contain quotes in tags for device 11111",
+                ],
+            ),
+            (
+                r#"[devices]
+11111 = { address = "01:23:45:67:89:AB", name = "Living room", offsets = { battery = 5 }, scale = { humidity = "a" } }
+22222 = { address = "CD:EF:01:23:45:67", name = "Kitchen", stale_after = 0.5, room_volume = 0, gatt = "yes" }
+33333 = { address = "89:AB:CD:EF:01:23", name = "Hall", averages = ["wind"], filters = { co2 = { median = 0 } } }
+44444 = { address = "89:AB:CD:EF:01:24", name = "Hallway", filters = { wind = {} } }
+55555 = { address = "89:AB:CD:EF:01:25", name = "Bedroom", filters = { co2 = { mean = 3 } } }
+66666 = { address = "89:AB:CD:EF:01:26", name = "Office", filters = { radon = 5 } }
+"#,
+                &[
+                    "2:76: battery can't be calibrated in offsets for device 11111",
+                    "2:112: invalid type: string \"a\", expected f64 for device 11111",
+                    "3:74: stale_after must be at least 1: 0.5 for device 22222",
+                    "3:93: room_volume must be a positive number: 0 for device 22222",
+                    "3:103: invalid type: string \"yes\", expected a boolean for device 22222",
+                    "4:69: unknown variant `wind`, expected one of `co2`, `radon`, `radiation_rate`, `temperature`, `humidity`, `pressure`, `battery` for device 33333",
+                    "4:107: median must be a whole number at least 1: 0 for co2 in filters for device 33333",
+                    "5:72: unknown variant `wind`, expected one of `co2`, `radon`, `radiation_rate`, `temperature`, `humidity`, `pressure`, `battery` for device 44444",
+                    "6:80: unknown field `mean`, expected one of `max_rate`, `median`, `ema` for device 55555",
+                    "7:79: invalid type: integer `5`, expected struct Filter for device 66666",
+                ],
+            ),
+            (
+                r#"settings = { units = "C" }
+
+[devices]
+11111 = { address = "01:23:45:67:89:AB", name = "Living room", settings = { smart_home = "on" } }
+22222 = { address = "CD:EF:01:23:45:67", name = "Kitchen", settings = { bluetooth_range = "far" } }
+33333 = { address = "89:AB:CD:EF:01:23", name = "Hall", settings = { temperature_unit = "K" } }
+"#,
+                &[
+                    "1:14: unknown field `units`, expected one of `interval`, `smart_home`, `bluetooth_range`",
+                    "4:90: invalid type: string \"on\", expected a boolean for device 11111",
+                    "5:91: unknown variant `far`, expected `normal` or `extended` for device 22222",
+                    "6:70: unknown field `temperature_unit`, expected one of `interval`, `smart_home`, `bluetooth_range` for device 33333",
+                ],
+            ),
+            (
+                r#"[devices]
+11111 = { address = "01:23:45:67:89:AB", name = "Living room" }
+
+[alerts]
+co2 = { metric = "co3", above = 1400, for = "soon" }
+humidity = { metric = "humidity", above = 60, below = 30 }
+radon = { metric = "radon", above = 150, devices = ["22222"], hysteresis = -1 }
+
+[ventilation]
+outdoor_co2 = -1
+outdoor_device = "99999"
+devices = ["11111", "22222"]
+"#,
+                &[
+                    "5:18: unknown variant `co3`, expected one of `co2`, `radon`, `radiation_rate`, `temperature`, `humidity`, `pressure`, `battery` in alert co2",
+                    "5:45: Invalid duration soon, expected e.g. 90s, 10m, 1h30m, or 7d in alert co2",
+                    "6:12: Exactly one of above and below must be set in alert humidity",
+                    "7:53: Unknown device 22222 in alert radon",
+                    "7:76: hysteresis must not be negative: -1 in alert radon",
+                    "10:15: outdoor_co2 must not be negative: -1 in ventilation",
+                    "11:18: Unknown device 99999 in ventilation",
+                    "12:21: Unknown device 22222 in ventilation",
+                ],
+            ),
+        ];
+        for (source, expected) in cases {
+            let diagnostics = Config::check(source, None)
+                .iter()
+                .map(|d| d.to_string())
+                .collect::<Vec<_>>();
+            assert_eq!(diagnostics, *expected, "{source}");
+        }
+
+        let seen = HashSet::from([BDAddr::from_str("01:23:45:67:89:AB").unwrap()]);
+        let diagnostics = Config::check(CONFIG, Some(&seen));
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].line, 4);
+        assert_eq!(
+            diagnostics[0].message,
+            "Device Kitchen (CD:EF:01:23:45:67) was not seen during the scan"
+        );
+
+        let diagnostics = Config::check("[devices]\n11111 = { address = ", None);
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].line, 2);
     }
 }
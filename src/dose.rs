@@ -0,0 +1,78 @@
+use crate::reading::Radiation;
+use serde::{Deserialize, Serialize};
+
+/// A radiation device's dose over its whole life. The device's own total
+/// starts again from zero whenever it restarts its dose measurement, which
+/// this notices so that the lifetime dose only ever goes up.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Accumulator {
+    /// Raw dose from the measurements before the device's latest restart
+    base: u64,
+    /// The device's raw total and duration at its latest reading
+    last: Option<(u32, u32)>,
+    /// How many times the device has restarted its dose measurement
+    pub resets: u32,
+}
+
+impl Accumulator {
+    /// Adds the latest reading, returning whether the device restarted its
+    /// dose measurement since the previous one
+    pub fn update(&mut self, radiation: &Radiation) -> bool {
+        let reset = match self.last {
+            Some((total, duration)) => {
+                radiation.raw_total < total || radiation.raw_duration < duration
+            }
+            None => false,
+        };
+
+        if reset && let Some((total, _)) = self.last {
+            self.base += u64::from(total);
+            self.resets += 1;
+        }
+
+        self.last = Some((radiation.raw_total, radiation.raw_duration));
+        reset
+    }
+
+    /// The lifetime dose, in the same units as `radiation_total`
+    pub fn lifetime(&self) -> f64 {
+        let total = self.last.map_or(0, |(total, _)| u64::from(total));
+        (self.base + total) as f64 / 1000000.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn radiation(raw_total: u32, raw_duration: u32) -> Radiation {
+        Radiation {
+            raw_total,
+            raw_duration,
+            raw_rate: 30,
+        }
+    }
+
+    #[test]
+    fn test_accumulator() {
+        let mut dose = Accumulator::default();
+        assert_eq!(dose.lifetime(), 0.0);
+
+        assert!(!dose.update(&radiation(48, 3420)));
+        assert!(!dose.update(&radiation(50, 3720)));
+        assert_eq!(dose.lifetime(), 0.00005);
+
+        // The total went backwards
+        assert!(dose.update(&radiation(2, 300)));
+        assert_eq!(dose.lifetime(), 0.000052);
+
+        // Only the duration went backwards, such as a restart that was
+        // missed until the total had caught up
+        assert!(dose.update(&radiation(60, 200)));
+        assert_eq!(dose.lifetime(), 0.000112);
+        assert_eq!(dose.resets, 2);
+
+        let json = serde_json::to_string(&dose).unwrap();
+        assert_eq!(serde_json::from_str::<Accumulator>(&json).unwrap(), dose);
+    }
+}
@@ -0,0 +1,71 @@
+use serde::{Deserialize, Deserializer};
+use std::time::Duration;
+
+/// Parses durations like `90s`, `10m`, `7d`, or `1h30m`
+pub fn parse(s: &str) -> Result<Duration, String> {
+    let invalid = || format!("Invalid duration {s}, expected e.g. 90s, 10m, 1h30m, or 7d");
+
+    let mut total = Duration::ZERO;
+    let mut rest = s.trim();
+    if rest.is_empty() {
+        return Err(invalid());
+    }
+
+    while !rest.is_empty() {
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .ok_or_else(invalid)?;
+        let number = rest[..digits].parse::<u64>().map_err(|_| invalid())?;
+        rest = &rest[digits..];
+
+        let unit = rest
+            .find(|c: char| c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let secs = match &rest[..unit] {
+            "s" => 1,
+            "m" => 60,
+            "h" => 60 * 60,
+            "d" => 24 * 60 * 60,
+            "w" => 7 * 24 * 60 * 60,
+            _ => return Err(invalid()),
+        };
+        rest = &rest[unit..];
+
+        let secs = number.checked_mul(secs).ok_or_else(invalid)?;
+        total = total
+            .checked_add(Duration::from_secs(secs))
+            .ok_or_else(invalid)?;
+    }
+
+    Ok(total)
+}
+
+/// For optional config fields written as duration strings
+pub fn deserialize_option<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Option::<String>::deserialize(deserializer)?
+        .map(|s| parse(&s).map_err(serde::de::Error::custom))
+        .transpose()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse("90s"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse("10m"), Ok(Duration::from_secs(600)));
+        assert_eq!(parse("1h30m"), Ok(Duration::from_secs(5400)));
+        assert_eq!(parse("7d"), Ok(Duration::from_secs(604800)));
+        assert_eq!(parse("2w"), Ok(Duration::from_secs(1209600)));
+        assert!(parse("").is_err());
+        assert!(parse("10").is_err());
+        assert!(parse("m").is_err());
+        assert!(parse("10 minutes").is_err());
+        assert!(parse("-5m").is_err());
+        assert!(parse("18446744073709551615s1s").is_err());
+    }
+}
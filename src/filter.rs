@@ -0,0 +1,226 @@
+use crate::metric::{Metric, Values};
+use serde::Deserialize;
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    time::Instant,
+};
+
+/// Smoothing and spike rejection for one metric of a device. The stages
+/// run in the order of the fields: the rate is clamped first, so that a
+/// spike is limited before it reaches the median or the moving average.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Filter {
+    /// The most the value can change per minute
+    pub max_rate: Option<f64>,
+    /// The median of this many readings
+    pub median: Option<usize>,
+    /// The weight of each new reading in an exponential moving average
+    pub ema: Option<f64>,
+}
+
+impl Filter {
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(max_rate) = self.max_rate {
+            Filter::validate_max_rate(max_rate)?;
+        }
+        if let Some(median) = self.median {
+            Filter::validate_median(median as f64)?;
+        }
+        if let Some(ema) = self.ema {
+            Filter::validate_ema(ema)?;
+        }
+        Ok(())
+    }
+
+    pub fn validate_max_rate(max_rate: f64) -> Result<(), String> {
+        if !(max_rate > 0.0 && max_rate.is_finite()) {
+            return Err(format!("max_rate must be a positive number: {max_rate}"));
+        }
+        Ok(())
+    }
+
+    pub fn validate_median(median: f64) -> Result<(), String> {
+        if !(median >= 1.0 && median.fract() == 0.0) {
+            return Err(format!(
+                "median must be a whole number at least 1: {median}"
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn validate_ema(ema: f64) -> Result<(), String> {
+        if !(ema > 0.0 && ema <= 1.0) {
+            return Err(format!("ema must be more than 0 and at most 1: {ema}"));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct State {
+    filter: Filter,
+    last: Option<(Instant, f64)>,
+    window: VecDeque<f64>,
+    ema: Option<f64>,
+}
+
+impl State {
+    fn new(filter: &Filter) -> Self {
+        Self {
+            filter: filter.clone(),
+            last: None,
+            window: VecDeque::new(),
+            ema: None,
+        }
+    }
+
+    fn apply(&mut self, mut value: f64, now: Instant) -> f64 {
+        if let Some(max_rate) = self.filter.max_rate {
+            if let Some((then, last)) = self.last {
+                let allowed = max_rate * now.saturating_duration_since(then).as_secs_f64() / 60.0;
+                value = value.clamp(last - allowed, last + allowed);
+            }
+            self.last = Some((now, value));
+        }
+
+        if let Some(median) = self.filter.median {
+            self.window.push_back(value);
+            while self.window.len() > median {
+                self.window.pop_front();
+            }
+            let mut sorted = self.window.iter().copied().collect::<Vec<_>>();
+            sorted.sort_by(f64::total_cmp);
+            let middle = sorted.len() / 2;
+            value = if sorted.len() % 2 == 0 {
+                (sorted[middle - 1] + sorted[middle]) / 2.0
+            } else {
+                sorted[middle]
+            };
+        }
+
+        if let Some(ema) = self.filter.ema {
+            value = match self.ema {
+                Some(previous) => ema * value + (1.0 - ema) * previous,
+                None => value,
+            };
+            self.ema = Some(value);
+        }
+
+        value
+    }
+}
+
+/// The state of every filter, by device ID and metric
+#[derive(Debug, Default)]
+pub struct Filters(HashMap<(String, Metric), State>);
+
+impl Filters {
+    /// Runs each of the device's filters over its value in the reading,
+    /// returning just the filtered values
+    pub fn apply(
+        &mut self,
+        device_id: &str,
+        filters: &BTreeMap<Metric, Filter>,
+        values: &Values,
+        now: Instant,
+    ) -> Values {
+        self.0
+            .retain(|(id, metric), _| id != device_id || filters.contains_key(metric));
+
+        let mut filtered = Values::default();
+        for (metric, filter) in filters {
+            let Some(value) = values.get(*metric) else {
+                continue;
+            };
+
+            let state = self
+                .0
+                .entry((device_id.to_string(), *metric))
+                .or_insert_with(|| State::new(filter));
+            // A changed filter starts again rather than mixing in old state
+            if state.filter != *filter {
+                *state = State::new(filter);
+            }
+
+            filtered.0.insert(*metric, state.apply(value, now));
+        }
+        filtered
+    }
+
+    /// Forgets devices that aren't in the list
+    pub fn retain<'a>(&mut self, ids: impl IntoIterator<Item = &'a str>) {
+        let ids = ids.into_iter().collect::<Vec<_>>();
+        self.0.retain(|(id, _), _| ids.contains(&id.as_str()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_filters() {
+        let start = Instant::now();
+        let at = |minutes: u64| start + Duration::from_secs(minutes * 60);
+        let co2 = |v: f64| Values(BTreeMap::from([(Metric::Co2, v)]));
+
+        let mut filters = Filters::default();
+        let mut run = |filter: Filter, readings: &[f64]| {
+            let config = BTreeMap::from([(Metric::Co2, filter)]);
+            readings
+                .iter()
+                .enumerate()
+                .map(|(i, v)| {
+                    filters
+                        .apply("11111", &config, &co2(*v), at(i as u64 * 5))
+                        .get(Metric::Co2)
+                        .unwrap()
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // A single spike is rejected by a median of 3
+        let median = Filter {
+            median: Some(3),
+            ..Default::default()
+        };
+        assert_eq!(
+            run(median.clone(), &[600.0, 610.0, 2400.0, 620.0, 630.0]),
+            vec![600.0, 605.0, 610.0, 620.0, 630.0]
+        );
+
+        // The same filter carries on from where it was
+        assert_eq!(run(median, &[640.0]), vec![630.0]);
+
+        // At most 20 ppm a minute, so 100 ppm every 5 minutes
+        let max_rate = Filter {
+            max_rate: Some(20.0),
+            ..Default::default()
+        };
+        assert_eq!(
+            run(max_rate, &[600.0, 2400.0, 650.0, 500.0]),
+            vec![600.0, 700.0, 650.0, 550.0]
+        );
+
+        let ema = Filter {
+            ema: Some(0.5),
+            ..Default::default()
+        };
+        assert_eq!(run(ema, &[600.0, 800.0, 800.0]), vec![600.0, 700.0, 750.0]);
+
+        // Metrics without a filter aren't output
+        let config = BTreeMap::from([(Metric::Radon, Filter::default())]);
+        assert_eq!(
+            filters.apply("11111", &config, &co2(600.0), at(0)),
+            Values::default()
+        );
+
+        assert!(Filter::validate_median(0.0).is_err());
+        assert!(Filter::validate_median(2.5).is_err());
+        assert!(Filter::validate_ema(0.0).is_err());
+        assert!(Filter::validate_ema(1.0).is_ok());
+        assert!(Filter::validate_max_rate(-1.0).is_err());
+    }
+}
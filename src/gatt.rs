@@ -0,0 +1,572 @@
+use crate::reading::{self, Device, ManufacturerData, Reading};
+use serde::Deserialize;
+use std::{collections::BTreeMap, time::Duration};
+use uuid::Uuid;
+
+/// The Aranet4's current readings, with the interval and age
+const ARANET4_CURRENT_READINGS: Uuid = Uuid::from_u128(0xf0cd3001_95da_4f4b_9ac8_aa55d312af0c);
+
+/// The standard Device Information characteristics
+pub const MODEL_NUMBER: Uuid = Uuid::from_u128(0x00002a24_0000_1000_8000_00805f9b34fb);
+pub const SERIAL_NUMBER: Uuid = Uuid::from_u128(0x00002a25_0000_1000_8000_00805f9b34fb);
+pub const FIRMWARE_REVISION: Uuid = Uuid::from_u128(0x00002a26_0000_1000_8000_00805f9b34fb);
+pub const HARDWARE_REVISION: Uuid = Uuid::from_u128(0x00002a27_0000_1000_8000_00805f9b34fb);
+
+/// Commands are written here, such as which history to download
+pub const COMMAND: Uuid = Uuid::from_u128(0xf0cd1402_95da_4f4b_9ac8_aa55d312af0c);
+
+/// The history asked for through `COMMAND`, read a chunk at a time
+pub const HISTORY: Uuid = Uuid::from_u128(0xf0cd2005_95da_4f4b_9ac8_aa55d312af0c);
+
+/// How many measurements are in the history
+pub const TOTAL_READINGS: Uuid = Uuid::from_u128(0xf0cd2001_95da_4f4b_9ac8_aa55d312af0c);
+
+/// The measurement interval in seconds
+pub const INTERVAL: Uuid = Uuid::from_u128(0xf0cd2002_95da_4f4b_9ac8_aa55d312af0c);
+
+/// Seconds since the last measurement
+pub const SINCE_UPDATE: Uuid = Uuid::from_u128(0xf0cd2004_95da_4f4b_9ac8_aa55d312af0c);
+
+/// Asks for the history of one parameter, starting from an index
+const HISTORY_COMMAND: u8 = 0x61;
+
+const SET_INTERVAL: u8 = 0x90;
+const SET_INTEGRATIONS: u8 = 0x91;
+const SET_RANGE: u8 = 0x92;
+
+/// The intervals devices can measure at, in seconds
+pub const INTERVALS: [u16; 4] = [60, 120, 300, 600];
+
+/// The characteristic to read for the device's current readings
+pub fn current_readings(device: Device) -> Result<Uuid, String> {
+    match device {
+        Device::Aranet4 => Ok(ARANET4_CURRENT_READINGS),
+        _ => Err(format!(
+            "Reading {device} over GATT is not yet supported, PRs welcome"
+        )),
+    }
+}
+
+/// Parses the value of the device's current readings characteristic
+pub fn parse_current_readings(device: Device, raw: &[u8]) -> Result<Reading, String> {
+    current_readings(device)?;
+
+    // co2, temperature, pressure, humidity, battery, status, interval, age
+    let raw: &[u8; 13] = raw
+        .get(..13)
+        .and_then(|raw| raw.try_into().ok())
+        .ok_or_else(|| {
+            format!(
+                "Current readings too short, expected 13 bytes, got {}",
+                raw.len()
+            )
+        })?;
+    let u16_at = |i: usize| u16::from_le_bytes([raw[i], raw[i + 1]]);
+
+    let age = u16_at(11);
+    let (instant, time) = reading::measured(Duration::from_secs(age as u64))?;
+
+    Ok(Reading {
+        device,
+        co2: Some(reading::co2(u16_at(0))),
+        radon: None,
+        radiation: None,
+        raw_temperature: Some(reading::temperature(u16_at(2))),
+        raw_pressure: Some(reading::pressure(u16_at(4))),
+        raw_humidity: Some(reading::humidity_v1(raw[6])),
+        battery: raw[7],
+        interval: u16_at(9),
+        age,
+        instant,
+        time,
+    })
+}
+
+/// A setting that can be changed by writing to `COMMAND`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Setting {
+    /// The measurement interval in seconds
+    Interval(u16),
+    /// Whether Smart Home integrations are on
+    Integrations(bool),
+    /// Whether the Bluetooth range is extended
+    ExtendedRange(bool),
+}
+
+impl Setting {
+    /// Checks that the device supports the setting and its value
+    pub fn validate(&self, device: Device) -> Result<(), String> {
+        if device != Device::Aranet4 {
+            return Err(format!(
+                "Changing settings on {device} is not yet supported, PRs welcome"
+            ));
+        }
+        if let Setting::Interval(interval) = self {
+            validate_interval((*interval).into())?;
+        }
+        Ok(())
+    }
+
+    /// The setting's key in the config's `settings`
+    pub fn name(&self) -> &'static str {
+        match self {
+            Setting::Interval(_) => "interval",
+            Setting::Integrations(_) => "smart_home",
+            Setting::ExtendedRange(_) => "bluetooth_range",
+        }
+    }
+
+    /// The setting's value, for reports
+    pub fn value(&self) -> String {
+        match self {
+            Setting::Interval(interval) => format!("{interval}s"),
+            Setting::Integrations(on) => String::from(if *on { "on" } else { "off" }),
+            Setting::ExtendedRange(extended) => {
+                String::from(if *extended { "extended" } else { "normal" })
+            }
+        }
+    }
+
+    /// What the device has this setting as now, given the interval read from
+    /// `INTERVAL` and its advertisement
+    pub fn current(&self, interval: u16, data: &ManufacturerData) -> Setting {
+        match self {
+            Setting::Interval(_) => Setting::Interval(interval),
+            Setting::Integrations(_) => Setting::Integrations(data.integrations),
+            Setting::ExtendedRange(_) => Setting::ExtendedRange(data.extended_range),
+        }
+    }
+
+    /// What to write to `COMMAND`
+    pub fn command(&self) -> [u8; 2] {
+        match self {
+            Setting::Interval(interval) => [SET_INTERVAL, (interval / 60) as u8],
+            Setting::Integrations(on) => [SET_INTEGRATIONS, *on as u8],
+            Setting::ExtendedRange(extended) => [SET_RANGE, *extended as u8],
+        }
+    }
+}
+
+impl std::fmt::Display for Setting {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Setting::Interval(interval) => write!(f, "interval to {interval}s"),
+            Setting::Integrations(on) => {
+                write!(
+                    f,
+                    "Smart Home integrations {}",
+                    if *on { "on" } else { "off" }
+                )
+            }
+            Setting::ExtendedRange(extended) => write!(
+                f,
+                "Bluetooth range to {}",
+                if *extended { "extended" } else { "normal" }
+            ),
+        }
+    }
+}
+
+/// Checks that the interval is one of `INTERVALS`
+pub fn validate_interval(interval: i64) -> Result<(), String> {
+    if !INTERVALS.iter().any(|i| i64::from(*i) == interval) {
+        return Err(format!(
+            "interval must be 60, 120, 300, or 600 seconds: {interval}"
+        ));
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Range {
+    Normal,
+    Extended,
+}
+
+/// The settings a device should have, from the config's `settings`. Any
+/// left out are left alone.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Settings {
+    /// The measurement interval in seconds
+    pub interval: Option<u16>,
+    pub smart_home: Option<bool>,
+    pub bluetooth_range: Option<Range>,
+}
+
+impl Settings {
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(interval) = self.interval {
+            validate_interval(interval.into())?;
+        }
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Settings::default()
+    }
+
+    /// These settings, with any left out taken from `other`
+    pub fn or(&self, other: &Settings) -> Settings {
+        Settings {
+            interval: self.interval.or(other.interval),
+            smart_home: self.smart_home.or(other.smart_home),
+            bluetooth_range: self.bluetooth_range.or(other.bluetooth_range),
+        }
+    }
+
+    /// Each setting to write to `COMMAND`
+    pub fn settings(&self) -> Vec<Setting> {
+        [
+            self.interval.map(Setting::Interval),
+            self.smart_home.map(Setting::Integrations),
+            self.bluetooth_range
+                .map(|range| Setting::ExtendedRange(range == Range::Extended)),
+        ]
+        .into_iter()
+        .flatten()
+        .collect()
+    }
+}
+
+/// Parses a little-endian u16 characteristic, like `INTERVAL`
+pub fn parse_u16(raw: &[u8]) -> Result<u16, String> {
+    match raw {
+        [a, b, ..] => Ok(u16::from_le_bytes([*a, *b])),
+        _ => Err(format!("Expected 2 bytes, got {}", raw.len())),
+    }
+}
+
+/// Parses a string characteristic, like `MODEL_NUMBER`, which may be
+/// padded with NULs
+pub fn parse_string(raw: &[u8]) -> String {
+    String::from_utf8_lossy(raw)
+        .trim_end_matches('\0')
+        .trim()
+        .to_string()
+}
+
+/// A measurement kept in a device's history
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Param {
+    Temperature,
+    Humidity,
+    Pressure,
+    Co2,
+}
+
+impl Param {
+    fn id(self) -> u8 {
+        match self {
+            Param::Temperature => 1,
+            Param::Humidity => 2,
+            Param::Pressure => 3,
+            Param::Co2 => 4,
+        }
+    }
+
+    /// Bytes per value in the history
+    fn size(self) -> usize {
+        match self {
+            Param::Humidity => 1,
+            _ => 2,
+        }
+    }
+}
+
+/// The history each type of device keeps
+pub fn history_params(device: Device) -> Result<&'static [Param], String> {
+    match device {
+        Device::Aranet4 => Ok(&[
+            Param::Temperature,
+            Param::Humidity,
+            Param::Pressure,
+            Param::Co2,
+        ]),
+        _ => Err(format!(
+            "Downloading history from {device} is not yet supported, PRs welcome"
+        )),
+    }
+}
+
+/// The command to write to `COMMAND` to download a parameter's history
+/// from `start`, which counts from 1
+pub fn history_command(param: Param, start: u16) -> [u8; 4] {
+    let [a, b] = start.to_le_bytes();
+    [HISTORY_COMMAND, param.id(), a, b]
+}
+
+/// One read of `HISTORY`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Chunk {
+    pub interval: u16,
+    pub total: u16,
+    /// Seconds since the last measurement
+    pub ago: u16,
+    /// The index of the first value, counting from 1
+    pub start: u16,
+    pub values: Vec<u16>,
+}
+
+pub fn parse_history_chunk(param: Param, raw: &[u8]) -> Result<Chunk, String> {
+    if raw.len() < 10 {
+        return Err(format!(
+            "History too short, expected at least 10 bytes, got {}",
+            raw.len()
+        ));
+    }
+    if raw[0] != param.id() {
+        return Err(format!(
+            "Expected history of {param:?} ({}), got {}",
+            param.id(),
+            raw[0]
+        ));
+    }
+
+    let u16_at = |i: usize| u16::from_le_bytes([raw[i], raw[i + 1]]);
+    let count = raw[9] as usize;
+    let data = &raw[10..];
+    if data.len() < count * param.size() {
+        return Err(format!(
+            "History has {} bytes for {count} values of {param:?}",
+            data.len()
+        ));
+    }
+
+    let values = data
+        .chunks_exact(param.size())
+        .take(count)
+        .map(|value| match value {
+            [a] => *a as u16,
+            [a, b] => u16::from_le_bytes([*a, *b]),
+            _ => unreachable!(),
+        })
+        .collect();
+
+    Ok(Chunk {
+        interval: u16_at(1),
+        total: u16_at(3),
+        ago: u16_at(5),
+        start: u16_at(7),
+        values,
+    })
+}
+
+/// Measurements downloaded from a device's history
+#[derive(Debug, Clone, PartialEq)]
+pub struct History {
+    pub device: Device,
+    pub interval: u16,
+    /// The index of the newest measurement, counting from 1
+    pub total: u16,
+    /// Seconds since the newest measurement
+    pub ago: u16,
+    /// The index of the first value of each parameter
+    pub start: u16,
+    pub values: BTreeMap<Param, Vec<u16>>,
+}
+
+impl History {
+    /// Turns the history into readings, oldest first, timed back from the
+    /// newest measurement. The history doesn't include the battery, so every
+    /// reading has the given level.
+    pub fn readings(&self, battery: u8) -> Result<Vec<Reading>, String> {
+        let count = self.values.values().map(Vec::len).min().unwrap_or(0);
+        let value = |param: Param, i: usize| self.values.get(&param).map(|values| values[i]);
+
+        (0..count)
+            .map(|i| {
+                let index = self.start as u64 + i as u64;
+                let age = self.ago as u64
+                    + (self.total as u64).saturating_sub(index) * self.interval as u64;
+                let (instant, time) = reading::measured(Duration::from_secs(age))?;
+
+                Ok(Reading {
+                    device: self.device,
+                    co2: value(Param::Co2, i).map(reading::co2),
+                    radon: None,
+                    radiation: None,
+                    raw_temperature: value(Param::Temperature, i).map(reading::temperature),
+                    raw_pressure: value(Param::Pressure, i).map(reading::pressure),
+                    raw_humidity: value(Param::Humidity, i).map(|v| reading::humidity_v1(v as u8)),
+                    battery,
+                    interval: self.interval,
+                    age: u16::try_from(age).unwrap_or(u16::MAX),
+                    instant,
+                    time,
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reading::Humidity;
+    use std::time::SystemTime;
+
+    #[test]
+    fn test_parse_current_readings() {
+        let raw = vec![
+            0xf0, 0x02, 0xc4, 0x01, 0xcd, 0x27, 0x38, 0x3c, 0x01, 0x3c, 0x00, 0x0d, 0x00,
+        ];
+
+        let reading = parse_current_readings(Device::Aranet4, &raw).unwrap();
+        assert_eq!(reading.device, Device::Aranet4);
+        assert_eq!(reading.co2, Some(Ok(752)));
+        assert_eq!(reading.raw_temperature, Some(Ok(452)));
+        assert_eq!(reading.raw_pressure, Some(Ok(10189)));
+        assert_eq!(reading.raw_humidity, Some(Ok(Humidity::V1(56))));
+        assert_eq!(reading.battery, 60);
+        assert_eq!(reading.interval, 60);
+        assert_eq!(reading.age, 13);
+
+        assert!(parse_current_readings(Device::Aranet4, &raw[..12]).is_err());
+        assert!(parse_current_readings(Device::AranetRadon, &raw).is_err());
+    }
+
+    #[test]
+    fn test_parse_values() {
+        assert_eq!(parse_u16(&[0x2c, 0x01]), Ok(300));
+        assert!(parse_u16(&[0x2c]).is_err());
+        assert_eq!(parse_string(b"Aranet4\0\0"), "Aranet4");
+    }
+
+    #[test]
+    fn test_settings() {
+        assert_eq!(Setting::Interval(300).command(), [0x90, 5]);
+        assert_eq!(Setting::Integrations(true).command(), [0x91, 1]);
+        assert_eq!(Setting::ExtendedRange(false).command(), [0x92, 0]);
+
+        assert!(Setting::Interval(60).validate(Device::Aranet4).is_ok());
+        assert!(Setting::Interval(90).validate(Device::Aranet4).is_err());
+        assert!(
+            Setting::Integrations(true)
+                .validate(Device::AranetRadon)
+                .is_err()
+        );
+
+        assert_eq!(Setting::Interval(60).to_string(), "interval to 60s");
+        assert_eq!(
+            Setting::ExtendedRange(true).to_string(),
+            "Bluetooth range to extended"
+        );
+    }
+
+    #[test]
+    fn test_history() {
+        assert_eq!(
+            history_command(Param::Co2, 0x0102),
+            [0x61, 0x04, 0x02, 0x01]
+        );
+
+        // CO₂ 752 and 760 from index 9 of 10, 30s after the last of 60s
+        let raw = [
+            0x04, 0x3c, 0x00, 0x0a, 0x00, 0x1e, 0x00, 0x09, 0x00, 0x02, 0xf0, 0x02, 0xf8, 0x02,
+        ];
+        let chunk = parse_history_chunk(Param::Co2, &raw).unwrap();
+        assert_eq!(
+            chunk,
+            Chunk {
+                interval: 60,
+                total: 10,
+                ago: 30,
+                start: 9,
+                values: vec![752, 760],
+            }
+        );
+        assert!(parse_history_chunk(Param::Humidity, &raw).is_err());
+        assert!(parse_history_chunk(Param::Co2, &raw[..12]).is_err());
+
+        let humidity = [
+            0x02, 0x3c, 0x00, 0x0a, 0x00, 0x1e, 0x00, 0x09, 0x00, 0x02, 0x38, 0x39,
+        ];
+        assert_eq!(
+            parse_history_chunk(Param::Humidity, &humidity)
+                .unwrap()
+                .values,
+            vec![56, 57]
+        );
+
+        let history = History {
+            device: Device::Aranet4,
+            interval: chunk.interval,
+            total: chunk.total,
+            ago: chunk.ago,
+            start: chunk.start,
+            values: BTreeMap::from([(Param::Co2, chunk.values), (Param::Humidity, vec![56, 57])]),
+        };
+        let readings = history.readings(80).unwrap();
+        assert_eq!(readings.len(), 2);
+        assert_eq!(readings[0].co2, Some(Ok(752)));
+        assert_eq!(readings[0].raw_humidity, Some(Ok(Humidity::V1(56))));
+        assert_eq!(readings[0].raw_temperature, None);
+        assert_eq!(readings[0].age, 90);
+        assert_eq!(readings[1].co2, Some(Ok(760)));
+        assert_eq!(readings[1].age, 30);
+
+        let gap = readings[1].time.duration_since(readings[0].time).unwrap();
+        assert!((gap.as_secs_f64() - 60.0).abs() < 1.0);
+        assert!(readings[1].time < SystemTime::now());
+    }
+
+    #[test]
+    fn test_desired_settings() {
+        let global = Settings {
+            interval: Some(300),
+            smart_home: Some(true),
+            ..Default::default()
+        };
+        let device = Settings {
+            interval: Some(60),
+            bluetooth_range: Some(Range::Extended),
+            ..Default::default()
+        };
+        let settings = device.or(&global);
+        assert_eq!(
+            settings.settings(),
+            vec![
+                Setting::Interval(60),
+                Setting::Integrations(true),
+                Setting::ExtendedRange(true),
+            ]
+        );
+        assert!(settings.validate().is_ok());
+        assert!(!settings.is_empty());
+        assert!(Settings::default().is_empty());
+
+        let bad = Settings {
+            interval: Some(90),
+            ..Default::default()
+        };
+        assert_eq!(
+            bad.validate(),
+            Err(String::from(
+                "interval must be 60, 120, 300, or 600 seconds: 90"
+            ))
+        );
+
+        // Flags 0x20 is integrations on, normal range
+        let mut raw = vec![0x20, 0x13, 0x04, 0x01];
+        raw.resize(22, 0);
+        let data = ManufacturerData::try_from(raw.as_slice()).unwrap();
+        let current = settings
+            .settings()
+            .iter()
+            .map(|setting| setting.current(120, &data))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            current,
+            vec![
+                Setting::Interval(120),
+                Setting::Integrations(true),
+                Setting::ExtendedRange(false),
+            ]
+        );
+        assert_eq!(current[0].name(), "interval");
+        assert_eq!(current[0].value(), "120s");
+        assert_eq!(current[2].value(), "normal");
+    }
+}
@@ -1,2 +1,15 @@
+pub mod alert;
+pub mod averages;
+pub mod battery;
+pub mod comfort;
 pub mod config;
+pub mod dose;
+pub mod duration;
+pub mod filter;
+pub mod gatt;
+pub mod metric;
+pub mod occupancy;
+pub mod output;
 pub mod reading;
+pub mod stale;
+pub mod ventilation;
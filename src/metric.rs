@@ -0,0 +1,182 @@
+use crate::{
+    comfort::Comfort,
+    output::Field,
+    reading::{Humidity, Reading},
+};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, str::FromStr};
+
+/// A single measured quantity, named as in the output
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Metric {
+    Co2,
+    Radon,
+    RadiationRate,
+    Temperature,
+    Humidity,
+    Pressure,
+    Battery,
+}
+
+impl Metric {
+    pub const ALL: [Metric; 7] = [
+        Metric::Co2,
+        Metric::Radon,
+        Metric::RadiationRate,
+        Metric::Temperature,
+        Metric::Humidity,
+        Metric::Pressure,
+        Metric::Battery,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Metric::Co2 => "co2",
+            Metric::Radon => "radon",
+            Metric::RadiationRate => "radiation_rate",
+            Metric::Temperature => "temperature",
+            Metric::Humidity => "humidity",
+            Metric::Pressure => "pressure",
+            Metric::Battery => "battery",
+        }
+    }
+
+    /// The value of this metric in the reading, in the units it's output in
+    pub fn value(&self, reading: &Reading) -> Option<f64> {
+        match self {
+            Metric::Co2 => reading.co2.and_then(Result::ok).map(f64::from),
+            Metric::Radon => reading.radon.and_then(Result::ok).map(f64::from),
+            Metric::RadiationRate => reading
+                .radiation
+                .as_ref()
+                .map(|radiation| radiation.raw_rate as f64 / 1000.0),
+            Metric::Temperature => reading.celsius().and_then(Result::ok).map(f64::from),
+            Metric::Humidity => match reading.raw_humidity {
+                Some(Ok(Humidity::V1(v))) => Some(v.into()),
+                Some(Ok(Humidity::V2(v))) => Some((v as f32 * 0.1).into()),
+                _ => None,
+            },
+            Metric::Pressure => reading.pressure_hpa().and_then(Result::ok).map(f64::from),
+            Metric::Battery => Some(reading.battery.into()),
+        }
+    }
+
+    /// The output field for a value of this metric. Values are written with
+    /// the same type as the raw reading would be, so that corrected values
+    /// don't change a field's type in the database.
+    pub fn field(&self, value: f64, reading: &Reading) -> Field {
+        match self {
+            Metric::Co2 | Metric::Radon | Metric::Battery => Field::Integer(value.round() as i64),
+            Metric::RadiationRate => Field::Float(value),
+            Metric::Temperature | Metric::Pressure => Field::Fixed(value, 1),
+            Metric::Humidity => match reading.raw_humidity {
+                Some(Ok(Humidity::V1(_))) => Field::Integer(value.round() as i64),
+                _ => Field::Fixed(value, 1),
+            },
+        }
+    }
+
+    /// Whether the metric can be corrected with offsets and scale in the
+    /// config
+    pub fn is_calibratable(&self) -> bool {
+        matches!(
+            self,
+            Metric::Co2 | Metric::Temperature | Metric::Humidity | Metric::Pressure
+        )
+    }
+}
+
+impl std::fmt::Display for Metric {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl FromStr for Metric {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Metric::ALL
+            .into_iter()
+            .find(|metric| metric.name() == s)
+            .ok_or_else(|| format!("Unknown metric: {s}"))
+    }
+}
+
+/// The value of each metric in a reading, which may have been corrected
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Values(pub BTreeMap<Metric, f64>);
+
+impl Values {
+    pub fn from_reading(reading: &Reading) -> Self {
+        Values(
+            Metric::ALL
+                .into_iter()
+                .filter_map(|metric| Some((metric, metric.value(reading)?)))
+                .collect(),
+        )
+    }
+
+    pub fn get(&self, metric: Metric) -> Option<f64> {
+        self.0.get(&metric).copied()
+    }
+
+    /// Dew point and the like, from the temperature and humidity
+    pub fn comfort(&self) -> Option<Comfort> {
+        Comfort::new(self.get(Metric::Temperature)?, self.get(Metric::Humidity)?)
+    }
+
+    /// Corrects each value as `value * scale + offset`
+    pub fn calibrate(&mut self, offsets: &BTreeMap<Metric, f64>, scale: &BTreeMap<Metric, f64>) {
+        for (metric, value) in self.0.iter_mut() {
+            if let Some(scale) = scale.get(metric) {
+                *value *= scale;
+            }
+            if let Some(offset) = offsets.get(metric) {
+                *value += offset;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_calibrate() {
+        let raw = vec![
+            0x21, 0x2c, 0x05, 0x01, 0x00, 0x0c, 0x01, 0x01, 0xf0, 0x02, 0xc4, 0x01, 0xcd, 0x27,
+            0x38, 0x3c, 0x01, 0x3c, 0x00, 0x0d, 0x00, 0x5d,
+        ];
+        let reading = Reading::try_from(raw.as_slice()).unwrap();
+
+        let mut values = Values::from_reading(&reading);
+        assert_eq!(values.get(Metric::Co2), Some(752.0));
+        assert_eq!(values.get(Metric::Radon), None);
+
+        let offsets = BTreeMap::from([(Metric::Temperature, -0.8)]);
+        let scale = BTreeMap::from([(Metric::Co2, 1.1), (Metric::Humidity, 0.5)]);
+        values.calibrate(&offsets, &scale);
+
+        assert_eq!(
+            Metric::Co2.field(values.get(Metric::Co2).unwrap(), &reading),
+            Field::Integer(827)
+        );
+        assert_eq!(
+            Metric::Temperature
+                .field(values.get(Metric::Temperature).unwrap(), &reading)
+                .to_string(),
+            "21.8"
+        );
+        assert_eq!(
+            Metric::Humidity.field(values.get(Metric::Humidity).unwrap(), &reading),
+            Field::Integer(28)
+        );
+        assert_eq!(
+            values.get(Metric::Pressure),
+            Metric::Pressure.value(&reading)
+        );
+    }
+}
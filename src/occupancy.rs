@@ -0,0 +1,91 @@
+use std::time::Instant;
+
+/// Litres of CO₂ a seated adult breathes out per hour
+pub const DEFAULT_CO2_PER_PERSON: f64 = 18.0;
+
+/// A room's volume and how much CO₂ each of its occupants adds
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Room {
+    /// m³
+    pub volume: f64,
+    /// Litres per hour
+    pub co2_per_person: f64,
+}
+
+/// Air exchange with the outdoors, which removes CO₂ as people add it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Ventilation {
+    pub ach: f64,
+    pub outdoor_co2: f64,
+}
+
+impl Room {
+    /// Estimates how many people are in the room from the change in CO₂
+    /// between two readings, from the mass balance
+    /// `volume * dC/dt = people * co2_per_person - ach * volume * (C - outdoor)`.
+    /// Without ventilation, only a rise in CO₂ counts people, so a room with
+    /// steady CO₂ is estimated as empty.
+    pub fn occupancy(
+        &self,
+        previous: (Instant, f64),
+        current: (Instant, f64),
+        ventilation: Option<Ventilation>,
+    ) -> Option<f64> {
+        let hours = current.0.checked_duration_since(previous.0)?.as_secs_f64() / 3600.0;
+        if hours == 0.0 {
+            return None;
+        }
+
+        // ppm per hour
+        let mut rate = (current.1 - previous.1) / hours;
+        if let Some(ventilation) = ventilation {
+            let mean = (current.1 + previous.1) / 2.0;
+            rate += ventilation.ach * (mean - ventilation.outdoor_co2).max(0.0);
+        }
+
+        // ppm is millionths of the volume, and co2_per_person is in litres
+        let people = self.volume * rate / (self.co2_per_person * 1000.0);
+        Some(people.max(0.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_occupancy() {
+        let room = Room {
+            volume: 50.0,
+            co2_per_person: DEFAULT_CO2_PER_PERSON,
+        };
+        let start = Instant::now();
+        let later = start + Duration::from_secs(5 * 60);
+
+        // 4 people add 72 L/h to 50 m³, which is 1440 ppm/h, or 120 ppm
+        // every 5 minutes
+        let people = room
+            .occupancy((start, 600.0), (later, 720.0), None)
+            .unwrap();
+        assert!((people - 4.0).abs() < 1e-9);
+
+        assert_eq!(
+            room.occupancy((start, 720.0), (later, 600.0), None),
+            Some(0.0)
+        );
+        assert_eq!(room.occupancy((start, 600.0), (start, 720.0), None), None);
+        assert_eq!(room.occupancy((later, 600.0), (start, 720.0), None), None);
+
+        // Steady at 1020 ppm with 1 air change per hour takes 600 ppm/h, or
+        // about 1.67 people
+        let ventilation = Ventilation {
+            ach: 1.0,
+            outdoor_co2: 420.0,
+        };
+        let people = room
+            .occupancy((start, 1020.0), (later, 1020.0), Some(ventilation))
+            .unwrap();
+        assert!((people - 600.0 * 50.0 / 18000.0).abs() < 1e-9);
+    }
+}
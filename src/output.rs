@@ -0,0 +1,280 @@
+use crate::{
+    comfort::Comfort,
+    config,
+    metric::{Metric, Values},
+    reading::{Device, Reading},
+};
+use std::time::SystemTime;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Field {
+    Integer(i64),
+    Float(f64),
+    /// A float written with a fixed number of decimal places
+    Fixed(f64, usize),
+    Boolean(bool),
+    String(String),
+}
+
+/// A single measurement with its tags and fields, which can be written in
+/// the InfluxDB line protocol
+#[derive(Debug, Clone, PartialEq)]
+pub struct Point {
+    pub measurement: String,
+    pub tags: Vec<(String, String)>,
+    pub fields: Vec<(String, Field)>,
+    pub time: SystemTime,
+}
+
+impl Point {
+    pub fn new(measurement: &str, time: SystemTime) -> Self {
+        Point {
+            measurement: measurement.to_string(),
+            tags: Vec::new(),
+            fields: Vec::new(),
+            time,
+        }
+    }
+
+    /// A point tagged with the device's name, type (if known), ID, and
+    /// configured tags
+    pub fn for_device(
+        measurement: &str,
+        device: &config::Device,
+        kind: Option<Device>,
+        time: SystemTime,
+    ) -> Self {
+        let mut point = Point::new(measurement, time);
+        point.tag("name", &device.name);
+        if let Some(kind) = kind {
+            point.tag("device", device_tag(kind));
+        }
+        point.tag("device_id", &device.id);
+        for (key, value) in &device.tags {
+            point.tag(key, value);
+        }
+        point
+    }
+
+    pub fn tag(&mut self, key: &str, value: &str) {
+        self.tags.push((key.to_string(), value.to_string()));
+    }
+
+    pub fn field(&mut self, key: &str, value: Field) {
+        self.fields.push((key.to_string(), value));
+    }
+
+    /// Adds the fields for each of the reading's valid measurements, using
+    /// the given values in place of the reading's own. Any value that was
+    /// corrected also has its raw value added, along with a `calibrated`
+    /// marker.
+    pub fn reading_fields(&mut self, reading: &Reading, values: &Values) {
+        self.metric_field(Metric::Co2, reading, values);
+        self.metric_field(Metric::Radon, reading, values);
+
+        if let Some(radiation) = &reading.radiation {
+            self.metric_field(Metric::RadiationRate, reading, values);
+            self.field(
+                "radiation_total",
+                Field::Float((radiation.raw_total as f64) / 1000000.0),
+            );
+            self.field(
+                "radiation_duration",
+                Field::Integer(radiation.raw_duration.into()),
+            );
+        }
+
+        self.metric_field(Metric::Temperature, reading, values);
+        self.metric_field(Metric::Humidity, reading, values);
+        self.metric_field(Metric::Pressure, reading, values);
+        self.metric_field(Metric::Battery, reading, values);
+
+        let mut calibrated = false;
+        for metric in Metric::ALL {
+            if let Some(raw) = metric.value(reading)
+                && values.get(metric).is_some_and(|value| value != raw)
+            {
+                self.field(&format!("{metric}_raw"), metric.field(raw, reading));
+                calibrated = true;
+            }
+        }
+
+        if calibrated {
+            self.field("calibrated", Field::Boolean(true));
+        }
+    }
+
+    /// Adds a field for each rolling average, named like `radon_24h`
+    pub fn average_fields(&mut self, means: &[(Metric, &str, f64)], reading: &Reading) {
+        for (metric, window, mean) in means {
+            self.field(&format!("{metric}_{window}"), metric.field(*mean, reading));
+        }
+    }
+
+    /// Adds a field for each filtered value, named like `co2_filtered`
+    pub fn filtered_fields(&mut self, filtered: &Values, reading: &Reading) {
+        for (metric, value) in &filtered.0 {
+            self.field(&format!("{metric}_filtered"), metric.field(*value, reading));
+        }
+    }
+
+    /// Adds fields for dew point and the like
+    pub fn comfort_fields(&mut self, comfort: &Comfort) {
+        self.field("dew_point", Field::Fixed(comfort.dew_point, 1));
+        self.field(
+            "absolute_humidity",
+            Field::Fixed(comfort.absolute_humidity, 1),
+        );
+        self.field("humidex", Field::Fixed(comfort.humidex, 1));
+        self.field("heat_index", Field::Fixed(comfort.heat_index, 1));
+        self.field("vpd", Field::Fixed(comfort.vpd, 2));
+    }
+
+    fn metric_field(&mut self, metric: Metric, reading: &Reading, values: &Values) {
+        if let Some(value) = values.get(metric) {
+            self.field(metric.name(), metric.field(value, reading));
+        }
+    }
+
+    pub fn nanos(&self) -> u128 {
+        self.time
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos()
+    }
+}
+
+/// The value of the `device` tag for each type of device
+pub fn device_tag(device: Device) -> &'static str {
+    match device {
+        Device::Aranet4 => "aranet4",
+        Device::Aranet2 => "aranet2",
+        Device::AranetRadiation => "aranet_radiation",
+        Device::AranetRadon => "aranet_radon",
+    }
+}
+
+fn escape(value: &str, special: &[char]) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if special.contains(&c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+impl std::fmt::Display for Field {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Field::Integer(v) => write!(f, "{v}i"),
+            Field::Float(v) => write!(f, "{v}"),
+            Field::Fixed(v, precision) => write!(f, "{v:.precision$}"),
+            Field::Boolean(v) => write!(f, "{v}"),
+            Field::String(v) => write!(f, "\"{}\"", escape(v, &['"', '\\'])),
+        }
+    }
+}
+
+impl std::fmt::Display for Point {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", escape(&self.measurement, &[',', ' ']))?;
+
+        for (key, value) in &self.tags {
+            write!(
+                f,
+                ",{}={}",
+                escape(key, &[',', '=', ' ']),
+                escape(value, &[',', '=', ' '])
+            )?;
+        }
+
+        for (i, (key, value)) in self.fields.iter().enumerate() {
+            let separator = if i == 0 { ' ' } else { ',' };
+            write!(f, "{separator}{}={value}", escape(key, &[',', '=', ' ']))?;
+        }
+
+        write!(f, " {}", self.nanos())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_line_protocol() {
+        let raw = vec![
+            0x21, 0x2c, 0x05, 0x01, 0x00, 0x0c, 0x01, 0x01, 0xf0, 0x02, 0xc4, 0x01, 0xcd, 0x27,
+            0x38, 0x3c, 0x01, 0x3c, 0x00, 0x0d, 0x00, 0x5d,
+        ];
+        let reading = Reading::try_from(raw.as_slice()).unwrap();
+
+        let time = SystemTime::UNIX_EPOCH + Duration::from_nanos(1746888802038113983);
+        let mut point = Point::new("aranet", time);
+        point.tag("name", "Dining room");
+        point.tag("device", device_tag(reading.device));
+        point.reading_fields(&reading, &Values::from_reading(&reading));
+        point.field("rssi", Field::Integer(-65));
+
+        assert_eq!(
+            point.to_string(),
+            "aranet,name=Dining\\ room,device=aranet4 co2=752i,temperature=22.6,humidity=56i,pressure=1018.9,battery=60i,rssi=-65i 1746888802038113983"
+        );
+    }
+
+    #[test]
+    fn test_calibrated() {
+        let raw = vec![
+            0x21, 0x2c, 0x05, 0x01, 0x00, 0x0c, 0x01, 0x01, 0xf0, 0x02, 0xc4, 0x01, 0xcd, 0x27,
+            0x38, 0x3c, 0x01, 0x3c, 0x00, 0x0d, 0x00, 0x5d,
+        ];
+        let reading = Reading::try_from(raw.as_slice()).unwrap();
+
+        let mut values = Values::from_reading(&reading);
+        values.calibrate(
+            &std::collections::BTreeMap::from([(Metric::Temperature, -0.8)]),
+            &Default::default(),
+        );
+
+        let mut point = Point::new("aranet", SystemTime::UNIX_EPOCH);
+        point.reading_fields(&reading, &values);
+
+        assert_eq!(
+            point.to_string(),
+            "aranet co2=752i,temperature=21.8,humidity=56i,pressure=1018.9,battery=60i,temperature_raw=22.6,calibrated=true 0"
+        );
+    }
+
+    #[test]
+    fn test_comfort() {
+        let raw = vec![
+            0x21, 0x2c, 0x05, 0x01, 0x00, 0x0c, 0x01, 0x01, 0xf0, 0x02, 0xc4, 0x01, 0xcd, 0x27,
+            0x38, 0x3c, 0x01, 0x3c, 0x00, 0x0d, 0x00, 0x5d,
+        ];
+        let reading = Reading::try_from(raw.as_slice()).unwrap();
+
+        let mut point = Point::new("aranet", SystemTime::UNIX_EPOCH);
+        point.comfort_fields(&reading.comfort().unwrap());
+
+        assert_eq!(
+            point.to_string(),
+            "aranet dew_point=13.4,absolute_humidity=11.2,humidex=25.6,heat_index=22.4,vpd=1.20 0"
+        );
+    }
+
+    #[test]
+    fn test_escaping() {
+        let mut point = Point::new("aranet alert", SystemTime::UNIX_EPOCH);
+        point.tag("zone", "east,west=1");
+        point.field("message", Field::String(String::from("say \"hi\" \\o/")));
+        point.field("ok", Field::Boolean(true));
+
+        assert_eq!(
+            point.to_string(),
+            "aranet\\ alert,zone=east\\,west\\=1 message=\"say \\\"hi\\\" \\\\o/\",ok=true 0"
+        );
+    }
+}
@@ -1,3 +1,4 @@
+use crate::{comfort::Comfort, metric::Values};
 use std::time::Duration;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -44,6 +45,52 @@ impl std::fmt::Display for Device {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Version {
+    pub major: u8,
+    pub minor: u8,
+    pub patch: u8,
+}
+
+impl std::fmt::Display for Version {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "v{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+/// The header at the start of every Aranet advertisement, which is present
+/// even when Smart Home integrations are disabled
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ManufacturerData {
+    pub device: Device,
+    pub integrations: bool,
+    pub version: Version,
+    pub calibration: Calibration,
+    /// Whether the device is set to extended Bluetooth range
+    pub extended_range: bool,
+}
+
+/// The state of the CO₂ sensor's calibration
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Calibration {
+    NotActive,
+    EndRequested,
+    InProgress,
+    Error,
+}
+
+impl std::fmt::Display for Calibration {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use Calibration::*;
+        match self {
+            NotActive => write!(f, "not active"),
+            EndRequested => write!(f, "end requested"),
+            InProgress => write!(f, "in progress"),
+            Error => write!(f, "error"),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct Radiation {
     pub raw_total: u32,
@@ -107,6 +154,71 @@ impl TryFrom<u8> for Device {
     }
 }
 
+impl Device {
+    /// Guesses the type of device from its advertised name, for when the
+    /// advertisement is too short to say
+    pub fn from_local_name(name: &str) -> Option<Self> {
+        if name.starts_with("Aranet4") {
+            Some(Device::Aranet4)
+        } else if name.starts_with("Aranet2") {
+            Some(Device::Aranet2)
+        } else if name.starts_with("AranetRn") {
+            Some(Device::AranetRadon)
+        } else if name.starts_with("Aranet Radiation") || name.starts_with("AranetRad") {
+            Some(Device::AranetRadiation)
+        } else {
+            None
+        }
+    }
+}
+
+/// Aranet devices advertise names like "Aranet4 1A2B3", which end in the
+/// device ID shown in the app
+pub fn id_from_local_name(name: &str) -> Option<&str> {
+    let (prefix, id) = name.rsplit_once(' ')?;
+    if prefix.starts_with("Aranet")
+        && !id.is_empty()
+        && id.chars().all(|c| c.is_ascii_alphanumeric())
+    {
+        Some(id)
+    } else {
+        None
+    }
+}
+
+impl TryFrom<&[u8]> for ManufacturerData {
+    type Error = String;
+
+    fn try_from(raw: &[u8]) -> Result<Self, Self::Error> {
+        // Aranet4 doesn't identify itself the same way. With integrations
+        // off, devices only send the 7-byte header.
+        let (device, header) = match raw.len() {
+            7 | 22 => (Device::Aranet4, raw),
+            n if n >= 8 => (Device::try_from(raw[0])?, &raw[1..]),
+            n => return Err(format!("Can't identify device from {n} bytes")),
+        };
+
+        let flags = header[0];
+
+        Ok(ManufacturerData {
+            device,
+            integrations: (flags >> 5) & 1 > 0,
+            version: Version {
+                major: header[3],
+                minor: header[2],
+                patch: header[1],
+            },
+            calibration: match (flags >> 2) & 0b11 {
+                0 => Calibration::NotActive,
+                1 => Calibration::EndRequested,
+                2 => Calibration::InProgress,
+                _ => Calibration::Error,
+            },
+            extended_range: (flags >> 6) & 1 > 0,
+        })
+    }
+}
+
 impl std::fmt::Display for Reading {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         if let Some(co2) = self.co2 {
@@ -189,6 +301,11 @@ impl Reading {
         })
     }
 
+    /// Dew point and the like, from the temperature and humidity
+    pub fn comfort(&self) -> Option<Comfort> {
+        Values::from_reading(self).comfort()
+    }
+
     pub fn is_repeat_reading(&self, newer: &Reading) -> bool {
         if self.co2 != newer.co2
             || self.radon != newer.radon
@@ -226,6 +343,55 @@ impl Reading {
     }
 }
 
+pub(crate) fn co2(raw: u16) -> Result<u16, ReadingError> {
+    if (raw >> 15) > 0 {
+        Err(ReadingError::Invalid)
+    } else {
+        Ok(raw)
+    }
+}
+
+pub(crate) fn temperature(raw: u16) -> Result<u16, ReadingError> {
+    if ((raw >> 14) & 1) > 0 {
+        Err(ReadingError::Invalid)
+    } else {
+        Ok(raw)
+    }
+}
+
+pub(crate) fn pressure(raw: u16) -> Result<u16, ReadingError> {
+    if (raw >> 15) > 0 {
+        Err(ReadingError::Invalid)
+    } else {
+        Ok(raw)
+    }
+}
+
+pub(crate) fn humidity_v1(raw: u8) -> Result<Humidity, ReadingError> {
+    if (raw >> 7) > 0 {
+        Err(ReadingError::Invalid)
+    } else {
+        Ok(Humidity::V1(raw))
+    }
+}
+
+/// When a reading that's `age` old was measured
+pub(crate) fn measured(
+    age: Duration,
+) -> Result<(std::time::Instant, std::time::SystemTime), String> {
+    let instant = std::time::Instant::now();
+    let instant = instant
+        .checked_sub(age)
+        .ok_or_else(|| "Failed to get current instant".to_string())?;
+
+    let time = std::time::SystemTime::now();
+    let time = time
+        .checked_sub(age)
+        .ok_or_else(|| "Failed to get current time".to_string())?;
+
+    Ok((instant, time))
+}
+
 impl TryFrom<&[u8]> for Reading {
     type Error = String;
 
@@ -262,14 +428,10 @@ impl TryFrom<&[u8]> for Reading {
         }
 
         let co2 = match device {
-            Device::Aranet4 => {
-                let co2 = u16::from_le_bytes([*bytes.next().unwrap(), *bytes.next().unwrap()]);
-                if (co2 >> 15) > 0 {
-                    Some(Err(ReadingError::Invalid))
-                } else {
-                    Some(Ok(co2))
-                }
-            }
+            Device::Aranet4 => Some(co2(u16::from_le_bytes([
+                *bytes.next().unwrap(),
+                *bytes.next().unwrap(),
+            ]))),
             _ => None,
         };
 
@@ -317,44 +479,25 @@ impl TryFrom<&[u8]> for Reading {
         };
 
         let raw_temperature = match device {
-            Device::Aranet4 | Device::AranetRadon => {
-                let raw_temperature =
-                    u16::from_le_bytes([*bytes.next().unwrap(), *bytes.next().unwrap()]);
-                let raw_temperature = if ((raw_temperature >> 14) & 1) > 0 {
-                    Err(ReadingError::Invalid)
-                } else {
-                    Ok(raw_temperature)
-                };
-                Some(raw_temperature)
-            }
+            Device::Aranet4 | Device::AranetRadon => Some(temperature(u16::from_le_bytes([
+                *bytes.next().unwrap(),
+                *bytes.next().unwrap(),
+            ]))),
             Device::AranetRadiation => None,
             Device::Aranet2 => unreachable!(),
         };
 
         let raw_pressure = match device {
-            Device::Aranet4 | Device::AranetRadon => {
-                let raw_pressure =
-                    u16::from_le_bytes([*bytes.next().unwrap(), *bytes.next().unwrap()]);
-                let raw_pressure = if (raw_pressure >> 15) > 0 {
-                    Err(ReadingError::Invalid)
-                } else {
-                    Ok(raw_pressure)
-                };
-                Some(raw_pressure)
-            }
+            Device::Aranet4 | Device::AranetRadon => Some(pressure(u16::from_le_bytes([
+                *bytes.next().unwrap(),
+                *bytes.next().unwrap(),
+            ]))),
             Device::AranetRadiation => None,
             Device::Aranet2 => unreachable!(),
         };
 
         let raw_humidity = match device {
-            Device::Aranet4 => {
-                let raw_humidity = *bytes.next().unwrap();
-                if (raw_humidity >> 7) > 0 {
-                    Some(Err(ReadingError::Invalid))
-                } else {
-                    Some(Ok(Humidity::V1(raw_humidity)))
-                }
-            }
+            Device::Aranet4 => Some(humidity_v1(*bytes.next().unwrap())),
             Device::AranetRadon => {
                 let raw_humidity =
                     u16::from_le_bytes([*bytes.next().unwrap(), *bytes.next().unwrap()]);
@@ -383,15 +526,7 @@ impl TryFrom<&[u8]> for Reading {
         let interval = u16::from_le_bytes([*bytes.next().unwrap(), *bytes.next().unwrap()]);
         let age = u16::from_le_bytes([*bytes.next().unwrap(), *bytes.next().unwrap()]);
 
-        let instant = std::time::Instant::now();
-        let instant = instant
-            .checked_sub(std::time::Duration::from_secs(age as u64))
-            .ok_or_else(|| "Failed to get current instant".to_string())?;
-
-        let time = std::time::SystemTime::now();
-        let time = time
-            .checked_sub(std::time::Duration::from_secs(age as u64))
-            .ok_or_else(|| "Failed to get current time".to_string())?;
+        let (instant, time) = measured(Duration::from_secs(age as u64))?;
 
         Ok(Reading {
             device,
@@ -436,6 +571,9 @@ mod tests {
         assert_eq!(reading.celsius(), Some(Ok(22.6)));
         assert_eq!(reading.fahrenheit(), Some(Ok(72.68)));
         assert_eq!(reading.pressure_hpa(), Some(Ok(1018.9)));
+
+        let comfort = reading.comfort().unwrap();
+        assert!((comfort.dew_point - 13.37).abs() < 0.01);
     }
 
     #[test]
@@ -460,6 +598,9 @@ mod tests {
         assert_eq!(reading.celsius(), Some(Ok(16.6)));
         assert_eq!(reading.fahrenheit(), Some(Ok(61.88)));
         assert_eq!(reading.pressure_hpa(), Some(Ok(1006.4)));
+
+        let comfort = reading.comfort().unwrap();
+        assert!((comfort.dew_point - 7.91).abs() < 0.01);
     }
 
     #[test]
@@ -493,6 +634,71 @@ mod tests {
         assert_eq!(reading.pressure_hpa(), None);
     }
 
+    #[test]
+    fn test_manufacturer_data() {
+        let raw = vec![
+            0x21, 0x2c, 0x05, 0x01, 0x00, 0x0c, 0x01, 0x01, 0xf0, 0x02, 0xc4, 0x01, 0xcd, 0x27,
+            0x38, 0x3c, 0x01, 0x3c, 0x00, 0x0d, 0x00, 0x5d,
+        ];
+
+        let data = ManufacturerData::try_from(raw.as_slice()).unwrap();
+        assert_eq!(data.device, Device::Aranet4);
+        assert!(data.integrations);
+        assert_eq!(data.version.to_string(), "v1.5.44");
+        assert_eq!(data.calibration, Calibration::NotActive);
+        assert!(!data.extended_range);
+
+        let mut flags = raw.clone();
+        flags[0] = 0x69;
+        let data = ManufacturerData::try_from(flags.as_slice()).unwrap();
+        assert_eq!(data.calibration, Calibration::InProgress);
+        assert!(data.extended_range);
+
+        let raw = vec![
+            0x03, 0x21, 0x04, 0x09, 0x01, 0x00, 0x00, 0x00, 0x18, 0x00, 0x4c, 0x01, 0x50, 0x27,
+            0x35, 0x02, 0x00, 0x64, 0x01, 0x58, 0x02, 0x41, 0x01, 0x45,
+        ];
+
+        let data = ManufacturerData::try_from(raw.as_slice()).unwrap();
+        assert_eq!(data.device, Device::AranetRadon);
+        assert!(data.integrations);
+        assert_eq!(data.version.to_string(), "v1.9.4");
+
+        // Integrations off
+        let data = ManufacturerData::try_from(&raw[..8]).unwrap();
+        assert_eq!(data.device, Device::AranetRadon);
+        assert_eq!(data.version.to_string(), "v1.9.4");
+        let data =
+            ManufacturerData::try_from(&[0x41, 0x2c, 0x05, 0x01, 0x00, 0x0c, 0x01][..]).unwrap();
+        assert_eq!(data.device, Device::Aranet4);
+        assert!(!data.integrations);
+        assert!(data.extended_range);
+        assert_eq!(data.version.to_string(), "v1.5.44");
+
+        assert!(ManufacturerData::try_from(&raw[..5]).is_err());
+    }
+
+    #[test]
+    fn test_device_from_local_name() {
+        assert_eq!(
+            Device::from_local_name("Aranet4 1A2B3"),
+            Some(Device::Aranet4)
+        );
+        assert_eq!(
+            Device::from_local_name("AranetRn+ 12345"),
+            Some(Device::AranetRadon)
+        );
+        assert_eq!(Device::from_local_name("Thermometer"), None);
+    }
+
+    #[test]
+    fn test_id_from_local_name() {
+        assert_eq!(id_from_local_name("Aranet4 1A2B3"), Some("1A2B3"));
+        assert_eq!(id_from_local_name("AranetRn+ 12345"), Some("12345"));
+        assert_eq!(id_from_local_name("Aranet4"), None);
+        assert_eq!(id_from_local_name("Living room 2"), None);
+    }
+
     #[test]
     fn test_short() {
         let raw = vec![
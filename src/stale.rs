@@ -0,0 +1,151 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+/// How many of its intervals a device can go without a reading before it's
+/// considered stale
+pub const DEFAULT_STALE_AFTER: f64 = 3.0;
+
+/// The interval assumed for a device that hasn't sent a reading yet, which is
+/// what Aranet devices use out of the box
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(300);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transition {
+    /// The device hasn't sent a reading for this long
+    Down(Duration),
+    /// The device sent a reading after going without for this long
+    Up(Duration),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// Not seen since tracking started
+    Pending,
+    Up,
+    Down,
+}
+
+#[derive(Debug)]
+struct Entry {
+    last_seen: Instant,
+    interval: Option<Duration>,
+    state: State,
+}
+
+/// Tracks when each device, by ID, last sent a reading, to notice when
+/// devices stop sending them and when they start again
+#[derive(Debug, Default)]
+pub struct Tracker {
+    entries: HashMap<String, Entry>,
+}
+
+impl Tracker {
+    /// Records a reading from the device, returning `Up` if it had been stale
+    pub fn seen(&mut self, id: &str, interval: Duration, now: Instant) -> Option<Transition> {
+        let entry = self.entries.entry(id.to_string()).or_insert(Entry {
+            last_seen: now,
+            interval: None,
+            state: State::Pending,
+        });
+
+        let transition = (entry.state == State::Down)
+            .then(|| Transition::Up(now.saturating_duration_since(entry.last_seen)));
+
+        entry.last_seen = now;
+        entry.interval = Some(interval);
+        entry.state = State::Up;
+        transition
+    }
+
+    /// Checks the given devices, with how many intervals each can go without
+    /// a reading, returning those that just went stale. Devices that weren't
+    /// tracked yet are tracked from now, and any others are forgotten.
+    pub fn check<'a>(
+        &mut self,
+        devices: impl IntoIterator<Item = (&'a str, f64)>,
+        now: Instant,
+    ) -> Vec<(String, Transition)> {
+        let devices = devices.into_iter().collect::<HashMap<_, _>>();
+        self.entries
+            .retain(|id, _| devices.contains_key(id.as_str()));
+
+        let mut transitions = Vec::new();
+        for (id, stale_after) in devices {
+            let entry = self.entries.entry(id.to_string()).or_insert(Entry {
+                last_seen: now,
+                interval: None,
+                state: State::Pending,
+            });
+
+            let silent = now.saturating_duration_since(entry.last_seen);
+            let limit = entry
+                .interval
+                .unwrap_or(DEFAULT_INTERVAL)
+                .mul_f64(stale_after);
+            if entry.state != State::Down && silent >= limit {
+                entry.state = State::Down;
+                transitions.push((id.to_string(), Transition::Down(silent)));
+            }
+        }
+
+        transitions.sort_by(|a, b| a.0.cmp(&b.0));
+        transitions
+    }
+
+    /// Whether the device has sent a reading and isn't stale
+    pub fn is_up(&self, id: &str) -> bool {
+        self.entries
+            .get(id)
+            .is_some_and(|entry| entry.state == State::Up)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tracker() {
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let interval = Duration::from_secs(60);
+        let devices = [("11111", 3.0), ("22222", 2.0)];
+
+        let mut tracker = Tracker::default();
+        assert_eq!(tracker.check(devices, at(0)), vec![]);
+        assert!(!tracker.is_up("11111"));
+
+        assert_eq!(tracker.seen("11111", interval, at(10)), None);
+        assert!(tracker.is_up("11111"));
+        assert_eq!(tracker.check(devices, at(189)), vec![]);
+
+        // Never seen, so stale after twice the default interval
+        assert_eq!(
+            tracker.check(devices, at(600)),
+            vec![
+                (
+                    String::from("11111"),
+                    Transition::Down(Duration::from_secs(590))
+                ),
+                (
+                    String::from("22222"),
+                    Transition::Down(Duration::from_secs(600))
+                ),
+            ]
+        );
+        assert!(!tracker.is_up("11111"));
+        assert_eq!(tracker.check(devices, at(700)), vec![]);
+
+        assert_eq!(
+            tracker.seen("11111", interval, at(710)),
+            Some(Transition::Up(Duration::from_secs(700)))
+        );
+        assert_eq!(tracker.seen("11111", interval, at(770)), None);
+
+        // Forgotten once no longer checked
+        assert_eq!(tracker.check([("11111", 3.0)], at(800)), vec![]);
+        assert!(!tracker.entries.contains_key("22222"));
+    }
+}
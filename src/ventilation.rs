@@ -0,0 +1,316 @@
+use crate::metric::{Metric, Values};
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+/// A typical outdoor CO₂ level in ppm
+pub const DEFAULT_OUTDOOR_CO2: f64 = 420.0;
+
+/// A decay has to start at least this far above outdoor levels to be fitted
+const MIN_EXCESS: f64 = 200.0;
+/// Readings closer than this to outdoor levels are mostly sensor noise
+const MIN_FIT_EXCESS: f64 = 50.0;
+/// How far CO₂ can rise during a decay, for sensor noise
+const TOLERANCE: f64 = 20.0;
+const MIN_POINTS: usize = 4;
+const MIN_DURATION: Duration = Duration::from_secs(15 * 60);
+/// A decay is ended and fitted after this long, so that a room staying flat
+/// above outdoor levels doesn't collect readings forever
+const MAX_DURATION: Duration = Duration::from_secs(6 * 60 * 60);
+/// ...or after this many readings, for devices reporting more often than
+/// every minute
+const MAX_POINTS: usize = 360;
+/// Fits worse than this probably weren't a steady decay
+const MIN_R_SQUARED: f64 = 0.9;
+
+/// The config's `[ventilation]` table
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Settings {
+    /// Outdoor CO₂ in ppm, used until the outdoor device has a reading
+    #[serde(default = "default_outdoor_co2")]
+    pub outdoor_co2: f64,
+    /// ID of a device outdoors whose CO₂ is used as the baseline
+    pub outdoor_device: Option<String>,
+    /// IDs of the devices to estimate, or every device if empty
+    #[serde(default)]
+    pub devices: Vec<String>,
+}
+
+fn default_outdoor_co2() -> f64 {
+    DEFAULT_OUTDOOR_CO2
+}
+
+impl Settings {
+    pub fn validate(&self) -> Result<(), String> {
+        if !(self.outdoor_co2 >= 0.0 && self.outdoor_co2.is_finite()) {
+            return Err(format!(
+                "outdoor_co2 must not be negative: {}",
+                self.outdoor_co2
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn applies_to(&self, device_id: &str) -> bool {
+        self.outdoor_device.as_deref() != Some(device_id)
+            && (self.devices.is_empty() || self.devices.iter().any(|id| id == device_id))
+    }
+}
+
+/// Air changes per hour, fitted to one period of CO₂ decay
+#[derive(Debug, Clone, PartialEq)]
+pub struct Estimate {
+    pub ach: f64,
+    /// How well the exponential fits, from 0 to 1
+    pub r_squared: f64,
+    pub duration: Duration,
+    pub start_co2: f64,
+    pub end_co2: f64,
+    pub outdoor_co2: f64,
+    /// When the decay ended
+    pub end: Instant,
+}
+
+/// Fits `co2 = outdoor + excess * e^(-ach * hours)` by least squares on the
+/// log of the excess over outdoor
+fn fit(points: &[(Instant, f64)], outdoor_co2: f64) -> Option<Estimate> {
+    let points = points
+        .iter()
+        .filter(|(_, co2)| co2 - outdoor_co2 >= MIN_FIT_EXCESS)
+        .collect::<Vec<_>>();
+
+    let &(start, start_co2) = *points.first()?;
+    let &(end, end_co2) = *points.last()?;
+    let duration = end.saturating_duration_since(start);
+    if points.len() < MIN_POINTS || duration < MIN_DURATION || start_co2 - outdoor_co2 < MIN_EXCESS
+    {
+        return None;
+    }
+
+    let xy = points
+        .iter()
+        .map(|(time, co2)| {
+            let hours = time.saturating_duration_since(start).as_secs_f64() / 3600.0;
+            (hours, (co2 - outdoor_co2).ln())
+        })
+        .collect::<Vec<_>>();
+
+    let n = xy.len() as f64;
+    let mean_x = xy.iter().map(|(x, _)| x).sum::<f64>() / n;
+    let mean_y = xy.iter().map(|(_, y)| y).sum::<f64>() / n;
+    let sxx = xy.iter().map(|(x, _)| (x - mean_x).powi(2)).sum::<f64>();
+    let sxy = xy
+        .iter()
+        .map(|(x, y)| (x - mean_x) * (y - mean_y))
+        .sum::<f64>();
+    let syy = xy.iter().map(|(_, y)| (y - mean_y).powi(2)).sum::<f64>();
+    if sxx == 0.0 || syy == 0.0 {
+        return None;
+    }
+
+    let slope = sxy / sxx;
+    let r_squared = sxy * sxy / (sxx * syy);
+    if slope >= 0.0 || r_squared < MIN_R_SQUARED {
+        return None;
+    }
+
+    Some(Estimate {
+        ach: -slope,
+        r_squared,
+        duration,
+        start_co2,
+        end_co2,
+        outdoor_co2,
+        end,
+    })
+}
+
+/// Follows one device's CO₂ for periods where it only falls
+#[derive(Debug, Default)]
+struct Detector {
+    points: Vec<(Instant, f64)>,
+    min: f64,
+}
+
+impl Detector {
+    /// Adds a reading, returning an estimate if it ended a decay
+    fn push(&mut self, now: Instant, co2: f64, outdoor_co2: f64) -> Option<Estimate> {
+        let falling = !self.points.is_empty() && co2 <= self.min + TOLERANCE;
+        let full = self.points.len() >= MAX_POINTS
+            || self
+                .points
+                .first()
+                .is_some_and(|(start, _)| now.saturating_duration_since(*start) >= MAX_DURATION);
+        if falling && !full && co2 - outdoor_co2 >= MIN_FIT_EXCESS {
+            self.points.push((now, co2));
+            self.min = self.min.min(co2);
+            return None;
+        }
+
+        // Rising again, down to outdoor levels, or gone on too long
+        let estimate = fit(&self.points, outdoor_co2);
+        self.points = vec![(now, co2)];
+        self.min = co2;
+        estimate
+    }
+}
+
+/// Estimates air changes per hour for each device as readings arrive
+#[derive(Debug)]
+pub struct Estimator {
+    settings: Settings,
+    outdoor_co2: Option<f64>,
+    /// By device ID
+    detectors: HashMap<String, Detector>,
+    /// The latest air changes per hour, by device ID
+    ach: HashMap<String, f64>,
+}
+
+impl Estimator {
+    pub fn new(settings: Settings) -> Self {
+        Estimator {
+            settings,
+            outdoor_co2: None,
+            detectors: HashMap::new(),
+            ach: HashMap::new(),
+        }
+    }
+
+    pub fn settings(&self) -> &Settings {
+        &self.settings
+    }
+
+    /// The outdoor device's latest CO₂, or the configured level
+    pub fn outdoor_co2(&self) -> f64 {
+        self.outdoor_co2.unwrap_or(self.settings.outdoor_co2)
+    }
+
+    /// The device's latest estimate of air changes per hour
+    pub fn ach(&self, device_id: &str) -> Option<f64> {
+        self.ach.get(device_id).copied()
+    }
+
+    /// Updates the device with its latest values, returning an estimate if
+    /// they ended a decay
+    pub fn observe(&mut self, device_id: &str, values: &Values, now: Instant) -> Option<Estimate> {
+        let co2 = values.get(Metric::Co2)?;
+
+        if self.settings.outdoor_device.as_deref() == Some(device_id) {
+            self.outdoor_co2 = Some(co2);
+            return None;
+        }
+        if !self.settings.applies_to(device_id) {
+            return None;
+        }
+
+        let outdoor_co2 = self.outdoor_co2();
+        let estimate = self
+            .detectors
+            .entry(device_id.to_string())
+            .or_default()
+            .push(now, co2, outdoor_co2)?;
+        self.ach.insert(device_id.to_string(), estimate.ach);
+        Some(estimate)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    fn settings(source: &str) -> Settings {
+        toml::from_str(source).unwrap()
+    }
+
+    fn co2(value: f64) -> Values {
+        Values(BTreeMap::from([(Metric::Co2, value)]))
+    }
+
+    #[test]
+    fn test_decay() {
+        let mut estimator = Estimator::new(settings(""));
+        let start = Instant::now();
+        let at = |mins: u64| start + Duration::from_secs(mins * 60);
+
+        // Occupied, then emptied at 20 minutes with 2 air changes per hour
+        for mins in (0..=20).step_by(5) {
+            let value = 620.0 + mins as f64 * 40.0;
+            assert_eq!(estimator.observe("11111", &co2(value), at(mins)), None);
+        }
+        for mins in (25..=80).step_by(5) {
+            let hours = (mins - 20) as f64 / 60.0;
+            let value = 420.0 + 1000.0 * (-2.0 * hours).exp();
+            assert_eq!(estimator.observe("11111", &co2(value), at(mins)), None);
+        }
+
+        let estimate = estimator.observe("11111", &co2(900.0), at(85)).unwrap();
+        assert!((estimate.ach - 2.0).abs() < 1e-6);
+        assert!(estimate.r_squared > 0.999);
+        assert_eq!(estimate.start_co2, 1420.0);
+        assert_eq!(estimate.duration, Duration::from_secs(60 * 60));
+        assert_eq!(estimate.end, at(80));
+        assert_eq!(estimator.ach("11111"), Some(estimate.ach));
+
+        // Too small a decay to fit
+        assert_eq!(estimator.observe("11111", &co2(800.0), at(90)), None);
+        assert_eq!(estimator.observe("11111", &co2(700.0), at(120)), None);
+        assert_eq!(estimator.observe("11111", &co2(900.0), at(125)), None);
+    }
+
+    #[test]
+    fn test_max_duration() {
+        let mut estimator = Estimator::new(settings(""));
+        let start = Instant::now();
+        let at = |mins: u64| start + Duration::from_secs(mins * 60);
+        let points = |estimator: &Estimator| estimator.detectors["11111"].points.len();
+
+        // Flat above outdoor levels for two days
+        for mins in (0..=2 * 24 * 60).step_by(5) {
+            assert_eq!(estimator.observe("11111", &co2(700.0), at(mins)), None);
+            assert!(points(&estimator) <= 6 * 12 + 1);
+        }
+
+        // A slow decay is fitted once it's gone on for 6 hours
+        let mut estimator = Estimator::new(settings(""));
+        let mut estimates = Vec::new();
+        for mins in (0..=7 * 60).step_by(5) {
+            let value = 420.0 + 1000.0 * (-0.2 * mins as f64 / 60.0).exp();
+            estimates.extend(estimator.observe("11111", &co2(value), at(mins)));
+        }
+        assert_eq!(estimates.len(), 1);
+        assert!((estimates[0].ach - 0.2).abs() < 1e-6);
+        assert_eq!(estimates[0].end, at(6 * 60 - 5));
+        // The next decay starts from the reading that ended it
+        assert_eq!(points(&estimator), 13);
+    }
+
+    #[test]
+    fn test_outdoor_device() {
+        let mut estimator = Estimator::new(settings(
+            r#"
+outdoor_co2 = 400
+outdoor_device = "99999"
+devices = ["11111"]
+"#,
+        ));
+        let start = Instant::now();
+        assert_eq!(estimator.outdoor_co2(), 400.0);
+        assert_eq!(estimator.observe("99999", &co2(450.0), start), None);
+        assert_eq!(estimator.outdoor_co2(), 450.0);
+        assert!(!estimator.settings().applies_to("99999"));
+        assert!(!estimator.settings().applies_to("22222"));
+
+        let at = |mins: u64| start + Duration::from_secs(mins * 60);
+        for mins in (0..=40).step_by(5) {
+            let value = 450.0 + 1000.0 * (-0.5 * mins as f64 / 60.0).exp();
+            estimator.observe("11111", &co2(value), at(mins));
+        }
+        let estimate = estimator.observe("11111", &co2(1500.0), at(45)).unwrap();
+        assert!((estimate.ach - 0.5).abs() < 1e-6);
+        assert_eq!(estimate.outdoor_co2, 450.0);
+    }
+}